@@ -0,0 +1,111 @@
+// dispatch counts and run times for arithmetic-heavy loops, as compiled and
+// after Chunk::optimize has fused OpConstant + OpAdd/OpSubtract pairs into
+// superinstructions, each timed with the checked loop that step() and
+// run_for() use, with the verified loop run() takes for verified chunks and
+// on the register machine, after translating outside the timed part.
+// run with `cargo bench --bench dispatch`; the programs and counts are fixed,
+// so only the times vary between machines
+use std::hint::black_box;
+use std::io;
+use std::time::{Duration, Instant};
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::countdown;
+use rust_vm_project::{
+    Chunk, ChunkBuilder, InterpretResult, OpCode, Profiler, RegisterMachine, StepResult, Value, VirtualMachine,
+};
+
+const ITERATIONS: i64 = 1_000_000;
+const SAMPLES: usize = 7;
+
+// keeps a running total next to the counter: total += counter * 3 + 7 - 2
+fn weighted_sum(n: i64) -> Chunk {
+    let mut b = ChunkBuilder::<Value>::default();
+    b.emit_constant(Value::Int(0)).emit_constant(Value::Int(n));
+    let top = b.loop_label();
+    b.emit_op(OpCode::OpDup);
+    let done = b.emit_jump_if_false();
+    // total n -> n total n -> n total+n*3+5
+    b.emit_op(OpCode::OpSwap).emit_op(OpCode::OpOver);
+    b.emit_constant(Value::Int(3)).emit_op(OpCode::OpMultiply);
+    b.emit_constant(Value::Int(7)).emit_op(OpCode::OpAdd);
+    b.emit_constant(Value::Int(2)).emit_op(OpCode::OpSubtract);
+    b.emit_op(OpCode::OpAdd);
+    // n total -> total n-1
+    b.emit_op(OpCode::OpSwap).emit_constant(Value::Int(1)).emit_op(OpCode::OpSubtract);
+    b.emit_loop(top);
+    b.patch(done);
+    b.emit_op(OpCode::OpReturn);
+    b.finish().unwrap()
+}
+
+fn dispatches(chunk: &Chunk) -> u64 {
+    let mut vm = VirtualMachine::builder().profiler(Profiler::new()).output(Box::new(io::sink())).build();
+    assert_eq!(vm.interpret(chunk.clone()), InterpretResult::InterpretSuccess);
+    vm.profiler().unwrap().total_instructions()
+}
+
+#[derive(Clone, Copy)]
+enum Engine {
+    Checked,
+    Verified,
+    Register,
+}
+
+// median of SAMPLES uninstrumented runs
+fn time(chunk: &Chunk, engine: Engine) -> Duration {
+    let program = chunk.to_registers().unwrap();
+    let mut times: Vec<Duration> = (0..SAMPLES)
+        .map(|_| {
+            let mut vm = VirtualMachine::builder().output(Box::new(io::sink())).build();
+            let mut registers = RegisterMachine::new();
+            registers.set_output(Box::new(io::sink()));
+            let chunk = chunk.clone();
+            let start = Instant::now();
+            let result = match engine {
+                Engine::Checked => {
+                    vm.load(black_box(chunk));
+                    match black_box(vm.run_for(usize::MAX)) {
+                        StepResult::Finished(_) => InterpretResult::InterpretSuccess,
+                        _ => InterpretResult::InterpretRuntimeError,
+                    }
+                }
+                Engine::Verified => vm.interpret(black_box(chunk)),
+                Engine::Register => registers.run(black_box(&program)),
+            };
+            let elapsed = start.elapsed();
+            assert_eq!(black_box(result), InterpretResult::InterpretSuccess);
+            elapsed
+        })
+        .collect();
+    times.sort();
+    times[SAMPLES / 2]
+}
+
+fn main() {
+    println!(
+        "{:<14} {:>10} {:>12} {:>8} {:>10} {:>10} {:>10} {:>10} {:>8} {:>10} {:>8}",
+        "program", "variant", "dispatches", "of base", "checked", "ns/op", "verified", "ns/op", "speedup", "register", "speedup"
+    );
+    for (name, chunk) in [("countdown", countdown(Value::Int(ITERATIONS), Value::Int(1))), ("weighted_sum", weighted_sum(ITERATIONS))] {
+        let mut optimized = chunk.clone();
+        optimized.optimize(VirtualMachine::init_machine().config().arithmetic).unwrap();
+        let baseline = dispatches(&chunk);
+        for (variant, chunk) in [("compiled", &chunk), ("optimized", &optimized)] {
+            let count = dispatches(chunk);
+            let share = format!("{:.0}%", count as f64 * 100.0 / baseline as f64);
+            let [checked, verified, register] = [Engine::Checked, Engine::Verified, Engine::Register].map(|e| time(chunk, e));
+            let per_op = |d: Duration| d.as_nanos() as f64 / count as f64;
+            let speedup = |d: Duration| format!("{:.2}x", checked.as_secs_f64() / d.as_secs_f64());
+            println!(
+                "{name:<14} {variant:>10} {count:>12} {share:>8} {checked:>10.2?} {:>10.2} {verified:>10.2?} {:>10.2} {:>8} {register:>10.2?} {:>8}",
+                per_op(checked),
+                per_op(verified),
+                speedup(verified),
+                speedup(register)
+            );
+        }
+    }
+}
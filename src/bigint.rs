@@ -0,0 +1,429 @@
+// arbitrary-precision integers, just enough for Value to grow past i64
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+// sign and magnitude; the magnitude is little-endian base 2^32 limbs with no
+// high zero limbs, so zero is an empty magnitude and never negative
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    magnitude: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseBigIntError;
+
+impl fmt::Display for ParseBigIntError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid decimal integer")
+    }
+}
+
+impl std::error::Error for ParseBigIntError {}
+
+impl BigInt {
+    pub fn zero() -> Self {
+        BigInt { negative: false, magnitude: Vec::new() }
+    }
+
+    fn from_parts(negative: bool, mut magnitude: Vec<u32>) -> Self {
+        while magnitude.last() == Some(&0) {
+            magnitude.pop();
+        }
+        let negative = negative && !magnitude.is_empty();
+        BigInt { negative, magnitude }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.magnitude.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    // bytes allocated for the limbs
+    pub fn heap_size(&self) -> usize {
+        self.magnitude.capacity() * std::mem::size_of::<u32>()
+    }
+
+    // None when the value is outside the i64 range
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.magnitude.len() > 2 {
+            return None;
+        }
+        let unsigned = self.magnitude.iter().rev().fold(0u64, |acc, &limb| (acc << 32) | limb as u64);
+        if self.negative {
+            0i64.checked_sub_unsigned(unsigned)
+        } else {
+            i64::try_from(unsigned).ok()
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let unsigned = self.magnitude.iter().rev().fold(0.0, |acc, &limb| acc * 4294967296.0 + limb as f64);
+        if self.negative { -unsigned } else { unsigned }
+    }
+
+    pub fn neg(&self) -> BigInt {
+        BigInt::from_parts(!self.negative, self.magnitude.clone())
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::from_parts(self.negative, add_magnitudes(&self.magnitude, &other.magnitude));
+        }
+        // opposite signs: the larger magnitude decides the sign
+        match compare_magnitudes(&self.magnitude, &other.magnitude) {
+            Ordering::Less => BigInt::from_parts(other.negative, sub_magnitudes(&other.magnitude, &self.magnitude)),
+            _ => BigInt::from_parts(self.negative, sub_magnitudes(&self.magnitude, &other.magnitude)),
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> BigInt {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> BigInt {
+        BigInt::from_parts(self.negative != other.negative, mul_magnitudes(&self.magnitude, &other.magnitude))
+    }
+
+    pub fn pow(&self, mut exp: u32) -> BigInt {
+        let mut base = self.clone();
+        let mut acc = BigInt::from(1);
+        while exp > 0 {
+            if exp & 1 == 1 {
+                acc = acc.mul(&base);
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base = base.mul(&base);
+            }
+        }
+        acc
+    }
+
+    // bitwise operations see a negative value as two's complement with
+    // infinitely many leading one bits, like Python's ints
+    pub fn bit_and(&self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a & b)
+    }
+
+    pub fn bit_or(&self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a | b)
+    }
+
+    pub fn bit_xor(&self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a ^ b)
+    }
+
+    // !x is -x - 1
+    pub fn bit_not(&self) -> BigInt {
+        self.neg().sub(&BigInt::from(1))
+    }
+
+    fn bitwise(&self, other: &BigInt, op: impl Fn(u32, u32) -> u32) -> BigInt {
+        // one more limb than either magnitude, so the top bit is the sign
+        let len = self.magnitude.len().max(other.magnitude.len()) + 1;
+        let (a, b) = (self.twos_complement(len), other.twos_complement(len));
+        let limbs: Vec<u32> = a.iter().zip(&b).map(|(&x, &y)| op(x, y)).collect();
+        if limbs[len - 1] >> 31 == 1 {
+            BigInt::from_parts(true, negate_limbs(&limbs))
+        } else {
+            BigInt::from_parts(false, limbs)
+        }
+    }
+
+    fn twos_complement(&self, len: usize) -> Vec<u32> {
+        let mut limbs = self.magnitude.clone();
+        limbs.resize(len, 0);
+        if self.negative { negate_limbs(&limbs) } else { limbs }
+    }
+
+    // significant bits in the magnitude
+    pub fn bits(&self) -> u64 {
+        match self.magnitude.last() {
+            Some(top) => self.magnitude.len() as u64 * 32 - top.leading_zeros() as u64,
+            None => 0,
+        }
+    }
+
+    // quotient truncated toward zero and a remainder with the dividend's
+    // sign, like i64; None when dividing by zero
+    pub fn divmod(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (quotient, remainder) = divmod_magnitudes(&self.magnitude, &other.magnitude);
+        Some((
+            BigInt::from_parts(self.negative != other.negative, quotient),
+            BigInt::from_parts(self.negative, remainder),
+        ))
+    }
+}
+
+impl BigInt {
+    // sign byte, little-endian limb count, then the limbs
+    pub fn write_bytes(&self, out: &mut Vec<u8>) {
+        out.push(self.negative as u8);
+        out.extend_from_slice(&(self.magnitude.len() as u32).to_le_bytes());
+        for limb in &self.magnitude {
+            out.extend_from_slice(&limb.to_le_bytes());
+        }
+    }
+
+    // decodes a value from the start of bytes, and how many bytes it took
+    pub fn read_bytes(bytes: &[u8]) -> Option<(BigInt, usize)> {
+        let (&sign, rest) = bytes.split_first()?;
+        let len = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let limbs = rest.get(4..4 + len.checked_mul(4)?)?;
+        let magnitude = limbs.chunks_exact(4).map(|l| u32::from_le_bytes(l.try_into().unwrap())).collect();
+        Some((BigInt::from_parts(sign != 0, magnitude), 5 + len * 4))
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(value: i64) -> Self {
+        let unsigned = value.unsigned_abs();
+        BigInt::from_parts(value < 0, vec![unsigned as u32, (unsigned >> 32) as u32])
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitudes(&self.magnitude, &other.magnitude),
+            (true, true) => compare_magnitudes(&other.magnitude, &self.magnitude),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// nine decimal digits fit in a limb
+const DECIMAL_CHUNK: u32 = 1_000_000_000;
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_zero() {
+            return f.pad_integral(true, "", "0");
+        }
+        let mut chunks = Vec::new();
+        let mut rest = self.magnitude.clone();
+        while !rest.is_empty() {
+            let (quotient, remainder) = divmod_small(&rest, DECIMAL_CHUNK);
+            chunks.push(remainder);
+            rest = quotient;
+        }
+        let mut digits = chunks.pop().map(|c| c.to_string()).unwrap_or_default();
+        for chunk in chunks.iter().rev() {
+            digits.push_str(&format!("{chunk:09}"));
+        }
+        f.pad_integral(!self.negative, "", &digits)
+    }
+}
+
+impl FromStr for BigInt {
+    type Err = ParseBigIntError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseBigIntError);
+        }
+        let mut magnitude = Vec::new();
+        for digit in digits.bytes() {
+            magnitude = mul_add_small(&magnitude, 10, (digit - b'0') as u32);
+        }
+        Ok(BigInt::from_parts(negative, magnitude))
+    }
+}
+
+// !limbs + 1, wrapping at the width of limbs
+fn negate_limbs(limbs: &[u32]) -> Vec<u32> {
+    let mut carry = 1u64;
+    limbs
+        .iter()
+        .map(|&limb| {
+            let t = !limb as u64 + carry;
+            carry = t >> 32;
+            t as u32
+        })
+        .collect()
+}
+
+fn compare_magnitudes(a: &[u32], b: &[u32]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut out = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0u64;
+    for (i, &limb) in long.iter().enumerate() {
+        let sum = limb as u64 + short.get(i).copied().unwrap_or(0) as u64 + carry;
+        out.push(sum as u32);
+        carry = sum >> 32;
+    }
+    if carry > 0 {
+        out.push(carry as u32);
+    }
+    out
+}
+
+// a - b, where a >= b
+fn sub_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, &limb) in a.iter().enumerate() {
+        let mut diff = limb as i64 - b.get(i).copied().unwrap_or(0) as i64 - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        }
+        out.push(diff as u32);
+    }
+    out
+}
+
+fn mul_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return Vec::new();
+    }
+    let mut out = vec![0u32; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &y) in b.iter().enumerate() {
+            let t = x as u64 * y as u64 + out[i + j] as u64 + carry;
+            out[i + j] = t as u32;
+            carry = t >> 32;
+        }
+        out[i + b.len()] = carry as u32;
+    }
+    out
+}
+
+// a * factor + addend
+fn mul_add_small(a: &[u32], factor: u32, addend: u32) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len() + 1);
+    let mut carry = addend as u64;
+    for &limb in a {
+        let t = limb as u64 * factor as u64 + carry;
+        out.push(t as u32);
+        carry = t >> 32;
+    }
+    if carry > 0 {
+        out.push(carry as u32);
+    }
+    out
+}
+
+fn divmod_small(a: &[u32], divisor: u32) -> (Vec<u32>, u32) {
+    let mut quotient = vec![0u32; a.len()];
+    let mut remainder = 0u64;
+    for (i, &limb) in a.iter().enumerate().rev() {
+        let current = (remainder << 32) | limb as u64;
+        quotient[i] = (current / divisor as u64) as u32;
+        remainder = current % divisor as u64;
+    }
+    while quotient.last() == Some(&0) {
+        quotient.pop();
+    }
+    (quotient, remainder as u32)
+}
+
+// binary long division; b is not zero
+fn divmod_magnitudes(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if let [divisor] = b {
+        let (quotient, remainder) = divmod_small(a, *divisor);
+        let remainder = if remainder == 0 { Vec::new() } else { vec![remainder] };
+        return (quotient, remainder);
+    }
+    if compare_magnitudes(a, b) == Ordering::Less {
+        return (Vec::new(), a.to_vec());
+    }
+    let mut quotient = vec![0u32; a.len()];
+    let mut remainder: Vec<u32> = Vec::new();
+    for bit in (0..a.len() * 32).rev() {
+        // remainder = remainder * 2 + next bit of a
+        remainder = mul_add_small(&remainder, 2, (a[bit / 32] >> (bit % 32)) & 1);
+        if compare_magnitudes(&remainder, b) != Ordering::Less {
+            remainder = sub_magnitudes(&remainder, b);
+            while remainder.last() == Some(&0) {
+                remainder.pop();
+            }
+            quotient[bit / 32] |= 1 << (bit % 32);
+        }
+    }
+    while quotient.last() == Some(&0) {
+        quotient.pop();
+    }
+    (quotient, remainder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(text: &str) -> BigInt {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn arithmetic_and_formatting() {
+        let a = big("123456789012345678901234567890");
+        let b = big("-987654321098765432109876543210");
+        assert_eq!(a.add(&b).to_string(), "-864197532086419753208641975320");
+        assert_eq!(a.sub(&b).to_string(), "1111111110111111111011111111100");
+        assert_eq!(a.mul(&b).to_string(), "-121932631137021795226185032733622923332237463801111263526900");
+        let (q, r) = b.divmod(&a).unwrap();
+        assert_eq!((q.to_string(), r.to_string()), ("-8".to_string(), "-9000000000900000000090".to_string()));
+        assert_eq!(a.divmod(&BigInt::zero()), None);
+        assert_eq!(big("-0").to_string(), "0");
+        assert_eq!(format!("{:>6}", big("-42")), "   -42");
+        assert!("12a".parse::<BigInt>().is_err());
+        assert_eq!(big("-3").pow(41).to_string(), "-36472996377170786403");
+        assert_eq!(a.pow(0), BigInt::from(1));
+        assert_eq!((BigInt::from(1 << 20).bits(), BigInt::zero().bits()), (21, 0));
+        assert_eq!(a.bit_and(&b).to_string(), "121512828827855409466171785234");
+        assert_eq!(a.bit_or(&b).to_string(), "-985710360914275162674813760554");
+        assert_eq!(a.bit_xor(&b).to_string(), "-1107223189742130572140985545788");
+        assert_eq!(b.bit_not().to_string(), "987654321098765432109876543209");
+    }
+
+    #[test]
+    fn matches_i64_at_the_edges() {
+        for v in [0, 1, -1, 7, -7, i64::MAX, i64::MIN, 1 << 32, -(1 << 32)] {
+            let b = BigInt::from(v);
+            assert_eq!(b.to_i64(), Some(v));
+            assert_eq!(b.to_string(), v.to_string());
+            assert_eq!(b.to_f64(), v as f64);
+        }
+        let past = BigInt::from(i64::MAX).add(&BigInt::from(1));
+        assert_eq!(past.to_i64(), None);
+        assert_eq!(past.neg().to_i64(), Some(i64::MIN));
+        for (a, b) in [(-7, 2), (7, -2), (-7, -2), (i64::MIN + 1, 3)] {
+            let (q, r) = BigInt::from(a).divmod(&BigInt::from(b)).unwrap();
+            assert_eq!((q.to_i64(), r.to_i64()), (Some(a / b), Some(a % b)));
+        }
+        assert!(BigInt::from(-3) < BigInt::from(2));
+        assert!(past > BigInt::from(i64::MAX));
+
+        let mut bytes = Vec::new();
+        past.neg().write_bytes(&mut bytes);
+        assert_eq!(BigInt::read_bytes(&bytes), Some((past.neg(), bytes.len())));
+        assert_eq!(BigInt::read_bytes(&bytes[..bytes.len() - 1]), None);
+    }
+}
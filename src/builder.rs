@@ -0,0 +1,288 @@
+use std::fmt;
+
+use crate::{Chunk, Number, OpCode, Operands, Value, VerifyError, opcode_to_u8, operand_width};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    // emit_op was given an opcode that needs operands
+    MissingOperand { op: OpCode, offset: usize },
+    // emit_with_constant was given an opcode without a constant operand
+    NoConstantOperand { op: OpCode, offset: usize },
+    TooManyConstants,
+    JumpTooLarge { offset: usize },
+    UnpatchedJump { offset: usize },
+    UnknownLabel { offset: usize },
+    Invalid(VerifyError),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MissingOperand { op, offset } => {
+                write!(f, "{offset:04}: {op:?} needs operands, use the matching emit_* method")
+            }
+            BuildError::NoConstantOperand { op, offset } => {
+                write!(f, "{offset:04}: {op:?} does not take a constant operand")
+            }
+            BuildError::TooManyConstants => write!(f, "too many constants in one chunk"),
+            BuildError::JumpTooLarge { offset } => write!(f, "{offset:04}: jump distance does not fit in 16 bits"),
+            BuildError::UnpatchedJump { offset } => write!(f, "{offset:04}: jump was never patched"),
+            BuildError::UnknownLabel { offset } => write!(f, "{offset:04}: label does not belong to this builder"),
+            BuildError::Invalid(e) => write!(f, "invalid chunk: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+// how much had been emitted at some point, for `ChunkBuilder::rewind`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Mark {
+    code: usize,
+    values: usize,
+}
+
+// a forward jump waiting for its target; consumed by `ChunkBuilder::patch`
+#[must_use = "jumps must be patched before the chunk is finished"]
+#[derive(Debug, PartialEq, Eq)]
+pub struct JumpLabel {
+    offset: usize,
+}
+
+// the start of a loop body, target of `ChunkBuilder::emit_loop`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopLabel {
+    offset: usize,
+}
+
+// emits well-formed bytecode: every opcode gets exactly the operands it
+// needs, and `finish` verifies the result before handing out a `Chunk`
+#[derive(Debug)]
+pub struct ChunkBuilder<V = Value> {
+    chunk: Chunk<V>,
+    line: u8,
+    unpatched: Vec<usize>,
+    error: Option<BuildError>,
+}
+
+impl<V: Number> Default for ChunkBuilder<V> {
+    fn default() -> Self {
+        ChunkBuilder {
+            chunk: Chunk::new(),
+            line: 1,
+            unpatched: Vec::new(),
+            error: None,
+        }
+    }
+}
+
+impl<V: Number> ChunkBuilder<V> {
+    // line recorded for everything emitted from now on
+    pub fn set_line(&mut self, line: u8) -> &mut Self {
+        self.line = line;
+        self
+    }
+
+    // runs `f` with `line` as the current line, then restores the previous one
+    pub fn with_line(&mut self, line: u8, f: impl FnOnce(&mut Self)) -> &mut Self {
+        let saved = self.line;
+        self.line = line;
+        f(self);
+        self.line = saved;
+        self
+    }
+
+    pub fn emit_op(&mut self, op: OpCode) -> &mut Self {
+        if operand_width(op) != 0 {
+            let offset = self.chunk.code.len();
+            self.fail(BuildError::MissingOperand { op, offset });
+            return self;
+        }
+        self.write(opcode_to_u8(op));
+        self
+    }
+
+    pub fn emit_constant(&mut self, value: V) -> &mut Self {
+        self.emit_with_constant(OpCode::OpConstant, value)
+    }
+
+    // an instruction whose operand is a constant, such as OpAddConstant
+    pub fn emit_with_constant(&mut self, op: OpCode, value: V) -> &mut Self {
+        if op.operands() != Operands::Constant {
+            let offset = self.chunk.code.len();
+            self.fail(BuildError::NoConstantOperand { op, offset });
+            return self;
+        }
+        if self.chunk.values.len() > u8::MAX as usize {
+            self.fail(BuildError::TooManyConstants);
+            return self;
+        }
+        let index = self.chunk.add_constant(value);
+        self.write(opcode_to_u8(op));
+        self.write(index);
+        self
+    }
+
+    pub fn emit_jump(&mut self) -> JumpLabel {
+        self.jump(OpCode::OpJump)
+    }
+
+    // pops the condition and jumps when it is zero
+    pub fn emit_jump_if_false(&mut self) -> JumpLabel {
+        self.jump(OpCode::OpJumpIfFalse)
+    }
+
+    // points the jump at the next instruction to be emitted
+    pub fn patch(&mut self, label: JumpLabel) -> &mut Self {
+        let Some(pos) = self.unpatched.iter().position(|&o| o == label.offset) else {
+            self.fail(BuildError::UnknownLabel { offset: label.offset });
+            return self;
+        };
+        self.unpatched.swap_remove(pos);
+
+        let distance = self.chunk.code.len() - (label.offset + 3);
+        match u16::try_from(distance) {
+            Ok(d) => {
+                let [hi, lo] = d.to_be_bytes();
+                self.chunk.code[label.offset + 1] = hi;
+                self.chunk.code[label.offset + 2] = lo;
+            }
+            Err(_) => self.fail(BuildError::JumpTooLarge { offset: label.offset }),
+        }
+        self
+    }
+
+    // marks the next instruction as the target of a later `emit_loop`
+    pub fn loop_label(&self) -> LoopLabel {
+        LoopLabel { offset: self.chunk.code.len() }
+    }
+
+    pub fn emit_loop(&mut self, start: LoopLabel) -> &mut Self {
+        let offset = self.chunk.code.len();
+        let Some(distance) = (offset + 3).checked_sub(start.offset) else {
+            self.fail(BuildError::UnknownLabel { offset: start.offset });
+            return self;
+        };
+        let Ok(d) = u16::try_from(distance) else {
+            self.fail(BuildError::JumpTooLarge { offset });
+            return self;
+        };
+        let [hi, lo] = d.to_be_bytes();
+        self.write(opcode_to_u8(OpCode::OpLoop));
+        self.write(hi);
+        self.write(lo);
+        self
+    }
+
+    pub fn finish(self) -> Result<Chunk<V>, BuildError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        if let Some(&offset) = self.unpatched.iter().min() {
+            return Err(BuildError::UnpatchedJump { offset });
+        }
+        self.chunk.verify().map_err(BuildError::Invalid)?;
+        Ok(self.chunk)
+    }
+
+    fn jump(&mut self, op: OpCode) -> JumpLabel {
+        let offset = self.chunk.code.len();
+        self.write(opcode_to_u8(op));
+        self.write(0xFF);
+        self.write(0xFF);
+        self.unpatched.push(offset);
+        JumpLabel { offset }
+    }
+
+    pub(crate) fn mark(&self) -> Mark {
+        Mark { code: self.chunk.code.len(), values: self.chunk.values.len() }
+    }
+
+    // drops the code and constants emitted since mark; the code must not
+    // hold a jump that is still waiting to be patched
+    pub(crate) fn rewind(&mut self, mark: Mark) {
+        debug_assert!(self.unpatched.iter().all(|&o| o < mark.code));
+        self.chunk.code.truncate(mark.code);
+        self.chunk.lines.truncate(mark.code);
+        self.chunk.values.truncate(mark.values);
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.chunk.write_to_chunk(byte, self.line);
+    }
+
+    // keeps the first error; later emits are still recorded but `finish` fails
+    fn fail(&mut self, error: BuildError) {
+        self.error.get_or_insert(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InterpretResult, VirtualMachine, u8_to_opcode};
+
+    #[test]
+    fn builds_the_same_bytes_as_manual_writes() {
+        let mut b = Chunk::builder();
+        b.set_line(10).emit_constant(Value::Int(15));
+        b.with_line(11, |b| {
+            b.emit_constant(Value::Int(42));
+        });
+        b.emit_op(OpCode::OpAdd).set_line(12).emit_op(OpCode::OpReturn);
+        let c = b.finish().unwrap();
+
+        assert_eq!(c.values, vec![Value::Int(15), Value::Int(42)]);
+        assert_eq!(c.lines, vec![10, 10, 11, 11, 10, 12]);
+        assert_eq!(u8_to_opcode(c.code[0]), Some(OpCode::OpConstant));
+        assert_eq!(c.code[1], 0);
+        assert_eq!(u8_to_opcode(c.code[4]), Some(OpCode::OpAdd));
+    }
+
+    #[test]
+    fn jumps_and_loops_run() {
+        // a taken conditional jump skips the 99, then a forward jump and a
+        // loop back land on the 7
+        let mut b = Chunk::builder();
+        b.emit_constant(Value::Int(0));
+        let skip = b.emit_jump_if_false();
+        b.emit_constant(Value::Int(99));
+        b.patch(skip);
+        let forward = b.emit_jump();
+        let top = b.loop_label();
+        b.emit_constant(Value::Int(7)).emit_op(OpCode::OpReturn);
+        b.patch(forward);
+        b.emit_loop(top);
+        let chunk = b.finish().unwrap();
+
+        let mut vm = VirtualMachine::init_machine();
+        assert_eq!(vm.interpret(chunk), InterpretResult::InterpretSuccess);
+        assert_eq!(vm.stack, vec![Value::Int(7)]);
+    }
+
+    #[test]
+    fn malformed_sequences_are_rejected() {
+        let mut b = Chunk::builder();
+        b.emit_op(OpCode::OpConstant).emit_op(OpCode::OpReturn);
+        assert_eq!(
+            b.finish().unwrap_err(),
+            BuildError::MissingOperand { op: OpCode::OpConstant, offset: 0 }
+        );
+
+        let mut b = Chunk::builder();
+        let _forgotten = b.emit_jump();
+        b.emit_op(OpCode::OpReturn);
+        assert_eq!(b.finish().unwrap_err(), BuildError::UnpatchedJump { offset: 0 });
+
+        let mut b = Chunk::builder();
+        for v in 0..=255 {
+            b.emit_constant(Value::Int(v));
+        }
+        b.emit_constant(Value::Int(0)).emit_op(OpCode::OpReturn);
+        assert_eq!(b.finish().unwrap_err(), BuildError::TooManyConstants);
+
+        let mut b = Chunk::builder();
+        b.emit_constant(Value::Int(1));
+        assert!(matches!(b.finish(), Err(BuildError::Invalid(VerifyError::FallsOffEnd { .. }))));
+    }
+}
@@ -0,0 +1,455 @@
+use std::fmt;
+
+use crate::builder::Mark;
+use crate::number::evaluate;
+use crate::{Arithmetic, BigInt, Chunk, ChunkBuilder, OpCode, Scanner, Token, TokenType, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub line: usize,
+    pub lexeme: String,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.lexeme.is_empty() {
+            write!(f, "[line {}] Error at end: {}", self.line, self.message)
+        } else {
+            write!(f, "[line {}] Error at '{}': {}", self.line, self.lexeme, self.message)
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+// how the compiler shapes the bytecode it emits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileOptions {
+    // replaces operations on constants with their result, so `15 + 42`
+    // compiles to one OpConstant; turn off to keep one instruction per operator
+    pub fold_constants: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions { fold_constants: true }
+    }
+}
+
+impl CompileOptions {
+    // every operator in the source keeps its own instruction
+    pub fn unoptimized() -> Self {
+        CompileOptions { fold_constants: false }
+    }
+}
+
+// binding power, lowest to highest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    None,
+    BitOr,  // |
+    BitXor, // ^
+    BitAnd, // &
+    Shift,  // << >>
+    Term,   // + -
+    Factor, // * / %
+    Unary,  // - ~
+    Power,  // **
+}
+
+impl Precedence {
+    fn next(self) -> Precedence {
+        match self {
+            Precedence::None => Precedence::BitOr,
+            Precedence::BitOr => Precedence::BitXor,
+            Precedence::BitXor => Precedence::BitAnd,
+            Precedence::BitAnd => Precedence::Shift,
+            Precedence::Shift => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary | Precedence::Power => Precedence::Power,
+        }
+    }
+}
+
+fn infix_precedence(token_type: TokenType) -> Precedence {
+    match token_type {
+        TokenType::TokenPipe => Precedence::BitOr,
+        TokenType::TokenCaret => Precedence::BitXor,
+        TokenType::TokenAmpersand => Precedence::BitAnd,
+        TokenType::TokenShiftLeft | TokenType::TokenShiftRight => Precedence::Shift,
+        TokenType::TokenPlus | TokenType::TokenMinus => Precedence::Term,
+        TokenType::TokenStar | TokenType::TokenSlash | TokenType::TokenPercent => Precedence::Factor,
+        TokenType::TokenStarStar => Precedence::Power,
+        _ => Precedence::None,
+    }
+}
+
+// compiles any number of `print expr;` statements followed by an optional
+// expression, whose value is left on the stack
+pub fn compile(source: &str) -> Result<Chunk, CompileError> {
+    compile_with(source, CompileOptions::default())
+}
+
+pub fn compile_with(source: &str, options: CompileOptions) -> Result<Chunk, CompileError> {
+    let mut parser = Parser {
+        scanner: Scanner::init_scanner(source),
+        current: None,
+        previous: None,
+        builder: Chunk::builder(),
+        options,
+    };
+    parser.advance()?;
+    while parser.current().token_type == TokenType::TokenPrint {
+        parser.advance()?;
+        parser.print_statement()?;
+    }
+    if parser.current().token_type != TokenType::TokenEof {
+        parser.expression()?;
+    }
+    // the implicit return belongs to the last line of the script, not to EOF
+    let line = parser.line_of_previous();
+    parser.consume(TokenType::TokenEof, "Expect end of expression.")?;
+    parser.builder.set_line(line).emit_op(OpCode::OpReturn);
+    parser.builder.finish().map_err(|e| CompileError {
+        line: 0,
+        lexeme: String::new(),
+        message: e.to_string(),
+    })
+}
+
+struct Parser {
+    scanner: Scanner,
+    current: Option<Token>,
+    previous: Option<Token>,
+    builder: ChunkBuilder,
+    options: CompileOptions,
+}
+
+impl Parser {
+    fn advance(&mut self) -> Result<(), CompileError> {
+        self.previous = self.current.take();
+        let token = self.scanner.scan_token();
+        if token.token_type == TokenType::TokenError {
+            return Err(CompileError {
+                line: token.line,
+                lexeme: String::new(),
+                message: String::from_utf8_lossy(&token.value).into_owned(),
+            });
+        }
+        self.current = Some(token);
+        Ok(())
+    }
+
+    fn current(&self) -> &Token {
+        self.current.as_ref().expect("parser is primed before use")
+    }
+
+    fn previous(&self) -> &Token {
+        self.previous.as_ref().expect("advance was called at least once")
+    }
+
+    fn consume(&mut self, token_type: TokenType, message: &str) -> Result<(), CompileError> {
+        if self.current().token_type == token_type {
+            return self.advance();
+        }
+        Err(self.error_at(self.current(), message))
+    }
+
+    fn error_at(&self, token: &Token, message: &str) -> CompileError {
+        CompileError {
+            line: token.line,
+            lexeme: String::from_utf8_lossy(&token.value).into_owned(),
+            message: message.to_string(),
+        }
+    }
+
+    // chunk lines are a byte wide; longer scripts share the last line number
+    fn line_of_previous(&self) -> u8 {
+        u8::try_from(self.previous().line).unwrap_or(u8::MAX)
+    }
+
+    fn print_statement(&mut self) -> Result<(), CompileError> {
+        let line = self.line_of_previous();
+        self.expression()?;
+        self.consume(TokenType::TokenSemicolon, "Expect ';' after value.")?;
+        self.builder.set_line(line).emit_op(OpCode::OpPrint);
+        Ok(())
+    }
+
+    // each parse method returns the expression's value when it is a constant
+    fn expression(&mut self) -> Result<Option<Value>, CompileError> {
+        self.parse_precedence(Precedence::BitOr)
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Result<Option<Value>, CompileError> {
+        let start = self.builder.mark();
+        self.advance()?;
+        let mut constant = self.prefix()?;
+        while precedence <= infix_precedence(self.current().token_type) {
+            self.advance()?;
+            constant = self.binary(start, constant)?;
+        }
+        Ok(constant)
+    }
+
+    fn prefix(&mut self) -> Result<Option<Value>, CompileError> {
+        match self.previous().token_type {
+            TokenType::TokenInteger | TokenType::TokenFloat => self.number(),
+            TokenType::TokenLeftParen => self.grouping(),
+            TokenType::TokenMinus | TokenType::TokenTilde => self.unary(),
+            _ => Err(self.error_at(self.previous(), "Expect expression.")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Option<Value>, CompileError> {
+        let text = String::from_utf8_lossy(&self.previous().value).into_owned();
+        let value = if self.previous().token_type == TokenType::TokenFloat {
+            Value::Float(text.parse().expect("the scanner only produces valid floats"))
+        } else {
+            // literals past i64 start out as bigints
+            match text.parse() {
+                Ok(i) => Value::Int(i),
+                Err(_) => Value::from(text.parse::<BigInt>().expect("the scanner only produces digits")),
+            }
+        };
+        let line = self.line_of_previous();
+        self.builder.set_line(line).emit_constant(value.clone());
+        Ok(Some(value))
+    }
+
+    fn grouping(&mut self) -> Result<Option<Value>, CompileError> {
+        let constant = self.expression()?;
+        self.consume(TokenType::TokenRightParen, "Expect ')' after expression.")?;
+        Ok(constant)
+    }
+
+    fn unary(&mut self) -> Result<Option<Value>, CompileError> {
+        let op = match self.previous().token_type {
+            TokenType::TokenTilde => OpCode::OpBitNot,
+            _ => OpCode::OpNegate,
+        };
+        let line = self.line_of_previous();
+        let start = self.builder.mark();
+        let operand = self.parse_precedence(Precedence::Unary)?;
+        Ok(self.operation(start, op, line, &[operand]))
+    }
+
+    // start marks where the left operand's code begins
+    fn binary(&mut self, start: Mark, left: Option<Value>) -> Result<Option<Value>, CompileError> {
+        let operator = self.previous().token_type;
+        let line = self.line_of_previous();
+        // ** is right-associative and binds tighter than a unary minus on its
+        // left, so -2 ** 2 is -4, while its right operand may be negated:
+        // 2 ** -1 and 2 ** 3 ** 2 both parse
+        let right = match operator {
+            TokenType::TokenStarStar => Precedence::Unary,
+            _ => infix_precedence(operator).next(),
+        };
+        let right = self.parse_precedence(right)?;
+        let op = match operator {
+            TokenType::TokenPlus => OpCode::OpAdd,
+            TokenType::TokenMinus => OpCode::OpSubtract,
+            TokenType::TokenStar => OpCode::OpMultiply,
+            TokenType::TokenSlash => OpCode::OpDivide,
+            TokenType::TokenPercent => OpCode::OpModulo,
+            TokenType::TokenStarStar => OpCode::OpPower,
+            TokenType::TokenAmpersand => OpCode::OpBitAnd,
+            TokenType::TokenPipe => OpCode::OpBitOr,
+            TokenType::TokenCaret => OpCode::OpBitXor,
+            TokenType::TokenShiftLeft => OpCode::OpShl,
+            TokenType::TokenShiftRight => OpCode::OpShr,
+            _ => unreachable!("only operators with an infix precedence get here"),
+        };
+        Ok(self.operation(start, op, line, &[left, right]))
+    }
+
+    // emits op, or replaces the code of its constant operands, which starts
+    // at start, with the result
+    fn operation(&mut self, start: Mark, op: OpCode, line: u8, operands: &[Option<Value>]) -> Option<Value> {
+        let folded = match operands.iter().cloned().collect::<Option<Vec<Value>>>() {
+            Some(values) if self.options.fold_constants => fold(op, &values),
+            _ => None,
+        };
+        match folded {
+            Some(value) => {
+                self.builder.rewind(start);
+                self.builder.set_line(line).emit_constant(value.clone());
+                Some(value)
+            }
+            None => {
+                self.builder.set_line(line).emit_op(op);
+                None
+            }
+        }
+    }
+}
+
+// the VM's arithmetic mode isn't known until run time, so only results that
+// are the same in every mode are folded; an operation that fails, like a
+// division by zero, is left for the VM to report with its line
+fn fold(op: OpCode, operands: &[Value]) -> Option<Value> {
+    let modes = [Arithmetic::Wrapping, Arithmetic::Saturating, Arithmetic::Checked];
+    let results = modes.map(|mode| evaluate(op, operands, mode).and_then(Result::ok));
+    let [first, rest @ ..] = &results;
+    let first = first.as_ref()?;
+    rest.iter().all(|r| r.as_ref() == Some(first)).then(|| first.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorKind, InterpretResult, VirtualMachine};
+
+    fn eval(source: &str) -> Value {
+        let chunk = compile(source).unwrap();
+        let mut vm = VirtualMachine::init_machine();
+        assert_eq!(vm.interpret(chunk), InterpretResult::InterpretSuccess);
+        assert_eq!(vm.stack.len(), 1);
+        vm.stack[0].clone()
+    }
+
+    #[test]
+    fn precedence_and_grouping() {
+        assert_eq!(eval("1 + 2 * 3"), Value::Int(7));
+        assert_eq!(eval("(1 + 2) * 3"), Value::Int(9));
+        assert_eq!(eval("20 - 4 - 3"), Value::Int(13));
+        assert_eq!(eval("100 / 5 / 2"), Value::Int(10));
+        assert_eq!(eval("-(2) + 3"), Value::Int(1));
+    }
+
+    #[test]
+    fn integers_and_floats() {
+        assert_eq!(eval("42"), Value::Int(42));
+        assert_eq!(eval("42.0"), Value::Float(42.0));
+        assert_eq!(eval("7 / 2"), Value::Int(3));
+        assert_eq!(eval("7 / 2.0"), Value::Float(3.5));
+        assert_eq!(eval("-7 / 2"), Value::Int(-3));
+        assert_eq!(eval("9007199254740993 - 1"), Value::Int(9007199254740992));
+    }
+
+    #[test]
+    fn modulo_and_power() {
+        assert_eq!(eval("7 % 3"), Value::Int(1));
+        assert_eq!(eval("-7 % 3"), Value::Int(-1));
+        assert_eq!(eval("1 + 10 % 4 * 2"), Value::Int(5));
+        assert_eq!(eval("2 ** 10"), Value::Int(1024));
+        assert_eq!(eval("2 ** 3 ** 2"), Value::Int(512));
+        assert_eq!(eval("-2 ** 2"), Value::Int(-4));
+        assert_eq!(eval("(-2) ** 2"), Value::Int(4));
+        assert_eq!(eval("2 * 3 ** 2"), Value::Int(18));
+        assert_eq!(eval("2 ** -1"), Value::Int(0));
+        assert_eq!(eval("2.0 ** -1"), Value::Float(0.5));
+        assert_eq!(eval("2 ** 100").to_string(), "1267650600228229401496703205376");
+
+        // 2 | 3 | 2 | OpPower | OpPower: the rightmost power runs first
+        let chunk = compile_with("2 ** 3 ** 2", CompileOptions::unoptimized()).unwrap();
+        let power = OpCode::OpPower.byte();
+        assert_eq!(&chunk.code[6..9], &[power, power, OpCode::OpReturn.byte()]);
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        assert_eq!(eval("12 & 10"), Value::Int(8));
+        assert_eq!(eval("12 | 10"), Value::Int(14));
+        assert_eq!(eval("12 ^ 10"), Value::Int(6));
+        assert_eq!(eval("~0"), Value::Int(-1));
+        assert_eq!(eval("-~5"), Value::Int(6));
+        // | binds loosest, then ^, &, shifts and arithmetic
+        assert_eq!(eval("1 | 6 ^ 3 & 2"), Value::Int(5));
+        assert_eq!(eval("1 << 2 + 1"), Value::Int(8));
+        assert_eq!(eval("255 >> 4 << 4"), Value::Int(240));
+        assert_eq!(eval("1 << 64").to_string(), "18446744073709551616");
+        assert_eq!(eval("-1 >> 100"), Value::Int(-1));
+
+        let mut vm = VirtualMachine::init_machine();
+        assert_eq!(vm.interpret(compile("1.5 & 1").unwrap()), InterpretResult::InterpretRuntimeError);
+        assert_eq!(vm.runtime_error().unwrap().to_string(), "non-integer operand in OpBitAnd\n[line 1] in script");
+        assert_eq!(vm.interpret(compile("1 >> -1").unwrap()), InterpretResult::InterpretRuntimeError);
+        assert_eq!(vm.runtime_error().unwrap().kind, ErrorKind::NegativeShift(OpCode::OpShr));
+    }
+
+    #[test]
+    fn integers_grow_past_64_bits() {
+        assert_eq!(eval("9223372036854775807 + 1").to_string(), "9223372036854775808");
+        assert_eq!(eval("9223372036854775808 - 1"), Value::Int(i64::MAX));
+        assert_eq!(
+            eval("123456789012345678901234567890 * 1000 / 7").to_string(),
+            (123456789012345678901234567890i128 * 1000 / 7).to_string()
+        );
+        assert_eq!(eval("-9223372036854775808"), Value::Int(i64::MIN));
+    }
+
+    #[test]
+    fn folds_constant_operations() {
+        let ops = |chunk: &Chunk| -> Vec<OpCode> {
+            let mut offset = 0;
+            let mut ops = Vec::new();
+            while offset < chunk.len() {
+                let op = OpCode::from_byte(chunk.code[offset]).unwrap();
+                ops.push(op);
+                offset += 1 + op.operands().width();
+            }
+            ops
+        };
+        use OpCode::{OpAdd, OpConstant, OpDivide, OpReturn};
+
+        let chunk = compile("15 + 42").unwrap();
+        assert_eq!(ops(&chunk), [OpConstant, OpReturn]);
+        assert_eq!(chunk.values, [Value::Int(57)]);
+        assert_eq!(compile("-(2) + 3 * 4 ** 2 % 5 - ~(1 << 3)").unwrap().values, [Value::Int(10)]);
+        assert_eq!(compile("1 +\n2").unwrap().lines, [1, 1, 2]);
+
+        // errors stay in the bytecode, and only the constant part around them folds
+        let chunk = compile("(1 + 1) / 0 + 2 * 3").unwrap();
+        assert_eq!(ops(&chunk), [OpConstant, OpConstant, OpDivide, OpConstant, OpAdd, OpReturn]);
+        assert_eq!(chunk.values, [Value::Int(2), Value::Int(0), Value::Int(6)]);
+        let mut vm = VirtualMachine::init_machine();
+        assert_eq!(vm.interpret(chunk), InterpretResult::InterpretRuntimeError);
+        assert_eq!(vm.runtime_error().unwrap().kind, ErrorKind::DivisionByZero(OpDivide));
+
+        let chunk = compile_with("15 + 42", CompileOptions::unoptimized()).unwrap();
+        assert_eq!(ops(&chunk), [OpConstant, OpConstant, OpAdd, OpReturn]);
+    }
+
+    #[test]
+    fn records_source_lines() {
+        let chunk = compile_with("1 +\n2 *\n3", CompileOptions::unoptimized()).unwrap();
+        // 1 | 2 | 3 | OpMultiply (line 2) | OpAdd (line 1) | OpReturn
+        assert_eq!(chunk.lines, vec![1, 1, 2, 2, 3, 3, 2, 1, 3]);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let e = compile("1 +").unwrap_err();
+        assert_eq!(e.to_string(), "[line 1] Error at end: Expect expression.");
+
+        let e = compile("(1 + 2").unwrap_err();
+        assert_eq!(e.message, "Expect ')' after expression.");
+
+        let e = compile("\n)").unwrap_err();
+        assert_eq!(e.line, 2);
+        assert_eq!(e.lexeme, ")");
+        assert_eq!(e.message, "Expect expression.");
+
+        assert!(compile("1 2").is_err());
+
+        let e = compile("print 1").unwrap_err();
+        assert_eq!(e.message, "Expect ';' after value.");
+    }
+
+    #[test]
+    fn print_statements() {
+        let chunk = compile_with("print 1 + 2;\nprint 4;\n5", CompileOptions::unoptimized()).unwrap();
+        let prints: Vec<usize> = (0..chunk.len()).filter(|&o| chunk.code[o] == OpCode::OpPrint.byte()).collect();
+        assert_eq!(prints, vec![5, 8]);
+        assert_eq!(chunk.line_at(8), Some(2));
+
+        let mut vm = VirtualMachine::init_machine();
+        vm.set_output(Box::new(std::io::sink()));
+        vm.load(chunk);
+        assert_eq!(vm.run(), InterpretResult::InterpretSuccess);
+        assert_eq!(vm.stack(), &[Value::Int(5)]);
+    }
+}
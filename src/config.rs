@@ -0,0 +1,175 @@
+use std::io::{BufRead, Write};
+use std::marker::PhantomData;
+
+use crate::{Coverage, Number, Profiler, Value, VirtualMachine};
+
+// what the arithmetic opcodes do when a result does not fit in the value type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Arithmetic {
+    #[default]
+    Wrapping,   //wrap around at the type's bounds
+    Saturating, //clamp to the type's bounds
+    Checked,    //stop with an overflow runtime error
+}
+
+// per-VM resource limits and arithmetic policy; exceeding a limit stops the
+// script with a runtime error instead of taking down the host process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmConfig {
+    pub max_stack: usize,      //values on the stack at once
+    pub max_heap_bytes: usize, //bytes the values on the stack may own at once, see Number::heap_size
+    pub arithmetic: Arithmetic,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            max_stack: 64 * 1024,
+            max_heap_bytes: 64 * 1024 * 1024,
+            arithmetic: Arithmetic::Wrapping,
+        }
+    }
+}
+
+impl VmConfig {
+    // no limits beyond what the host can provide
+    pub fn unlimited() -> Self {
+        VmConfig {
+            max_stack: usize::MAX,
+            max_heap_bytes: usize::MAX,
+            arithmetic: Arithmetic::Wrapping,
+        }
+    }
+}
+
+// builds a VirtualMachine with its limits and instrumentation set up front.
+// GC thresholds and native functions belong here too once the VM has a
+// collector and natives to register; neither exists yet, so there are no
+// options for them
+pub struct VmBuilder<V = Value> {
+    config: VmConfig,
+    stack_capacity: usize,
+    trace: Option<Box<dyn Write>>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    fuel: Option<u64>,
+    output: Option<Box<dyn Write>>,
+    input: Option<Box<dyn BufRead>>,
+    value: PhantomData<V>,
+}
+
+impl<V> Default for VmBuilder<V> {
+    fn default() -> Self {
+        VmBuilder {
+            config: VmConfig::default(),
+            stack_capacity: 0,
+            trace: None,
+            profiler: None,
+            coverage: None,
+            fuel: None,
+            output: None,
+            input: None,
+            value: PhantomData,
+        }
+    }
+}
+
+impl<V: Number> VmBuilder<V> {
+    // replaces all limits at once
+    pub fn config(mut self, config: VmConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn max_stack(mut self, values: usize) -> Self {
+        self.config.max_stack = values;
+        self
+    }
+
+    pub fn max_heap_bytes(mut self, bytes: usize) -> Self {
+        self.config.max_heap_bytes = bytes;
+        self
+    }
+
+    pub fn arithmetic(mut self, arithmetic: Arithmetic) -> Self {
+        self.config.arithmetic = arithmetic;
+        self
+    }
+
+    // values to reserve room for up front, so small scripts never grow the stack
+    pub fn stack_capacity(mut self, values: usize) -> Self {
+        self.stack_capacity = values;
+        self
+    }
+
+    pub fn trace(mut self, sink: Box<dyn Write>) -> Self {
+        self.trace = Some(sink);
+        self
+    }
+
+    pub fn profiler(mut self, profiler: Profiler) -> Self {
+        self.profiler = Some(profiler);
+        self
+    }
+
+    pub fn coverage(mut self, coverage: Coverage) -> Self {
+        self.coverage = Some(coverage);
+        self
+    }
+
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    // where print writes, instead of stdout
+    pub fn output(mut self, sink: Box<dyn Write>) -> Self {
+        self.output = Some(sink);
+        self
+    }
+
+    // where lines are read from, instead of stdin
+    pub fn input(mut self, source: Box<dyn BufRead>) -> Self {
+        self.input = Some(source);
+        self
+    }
+
+    pub fn build(self) -> VirtualMachine<V> {
+        let mut vm = VirtualMachine::new();
+        vm.stack.reserve(self.stack_capacity.min(self.config.max_stack));
+        vm.config = self.config;
+        vm.trace = self.trace;
+        vm.profiler = self.profiler;
+        vm.coverage = self.coverage;
+        vm.fuel = self.fuel;
+        vm.output = self.output;
+        vm.input = self.input;
+        vm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Chunk, InterpretResult, OpCode};
+
+    #[test]
+    fn builder_applies_every_setting() {
+        let mut vm = VirtualMachine::builder()
+            .max_stack(8)
+            .stack_capacity(100)
+            .profiler(Profiler::new())
+            .fuel(10)
+            .build();
+        assert_eq!(vm.config(), &VmConfig { max_stack: 8, ..VmConfig::default() });
+        assert!(vm.stack.capacity() >= 8);
+        assert_eq!(vm.fuel(), Some(10));
+
+        let mut b = Chunk::builder();
+        b.emit_constant(Value::Int(2)).emit_op(OpCode::OpReturn);
+        assert_eq!(vm.interpret(b.finish().unwrap()), InterpretResult::InterpretSuccess);
+        assert_eq!(vm.stack(), &[Value::Int(2)]);
+        assert_eq!(vm.ip(), 3);
+        assert_eq!(vm.profiler().map(Profiler::total_instructions), Some(2));
+    }
+}
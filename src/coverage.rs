@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::{Chunk, u8_to_opcode};
+
+// per-offset execution counts for one source file, plus a copy of the
+// chunk's line table so offsets can be mapped back to lines afterwards
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct FileCoverage {
+    lines: Vec<u8>,
+    starts: Vec<bool>,
+    hits: Vec<u64>,
+}
+
+impl FileCoverage {
+    fn for_chunk<V>(chunk: &Chunk<V>) -> Self {
+        let mut starts = vec![false; chunk.code.len()];
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            starts[offset] = true;
+            offset += 1 + u8_to_opcode(chunk.code[offset]).map_or(0, |op| op.operands().width());
+        }
+        FileCoverage {
+            lines: chunk.lines.clone(),
+            starts,
+            hits: vec![0; chunk.code.len()],
+        }
+    }
+
+    // a line's count is that of its most executed instruction
+    fn line_hits(&self) -> BTreeMap<u8, u64> {
+        let mut lines = BTreeMap::new();
+        for offset in (0..self.hits.len()).filter(|&o| self.starts[o]) {
+            let hits = lines.entry(self.lines[offset]).or_insert(0);
+            *hits = (*hits).max(self.hits[offset]);
+        }
+        lines
+    }
+}
+
+// records which bytecode offsets ran, keyed by source file, and merges
+// the results of any number of runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    files: BTreeMap<String, FileCoverage>,
+    current: String,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new("script")
+    }
+}
+
+impl Coverage {
+    // `file` names the source the next runs are attributed to
+    pub fn new(file: &str) -> Self {
+        Coverage {
+            files: BTreeMap::new(),
+            current: file.to_string(),
+        }
+    }
+
+    pub fn set_file(&mut self, file: &str) {
+        self.current = file.to_string();
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(String::as_str)
+    }
+
+    pub(crate) fn record<V>(&mut self, chunk: &Chunk<V>, offset: usize) {
+        let file = self.files.entry(self.current.clone()).or_default();
+        // a different chunk for the same file starts over
+        if file.hits.len() != chunk.code.len() || file.lines != chunk.lines {
+            *file = FileCoverage::for_chunk(chunk);
+        }
+        file.hits[offset] += 1;
+    }
+
+    // adds the counts from other; runs of the same file must come from the same chunk
+    pub fn merge(&mut self, other: Coverage) {
+        for (name, theirs) in other.files {
+            match self.files.get_mut(&name) {
+                Some(ours) if ours.lines == theirs.lines => {
+                    for (a, b) in ours.hits.iter_mut().zip(theirs.hits) {
+                        *a += b;
+                    }
+                }
+                _ => {
+                    self.files.insert(name, theirs);
+                }
+            }
+        }
+    }
+
+    // times each executed bytecode offset ran
+    pub fn offset_hits(&self, file: &str) -> Vec<(usize, u64)> {
+        self.files.get(file).map_or_else(Vec::new, |f| {
+            (0..f.hits.len()).filter(|&o| f.starts[o]).map(|o| (o, f.hits[o])).collect()
+        })
+    }
+
+    // every line with code in it, and how often it ran
+    pub fn line_hits(&self, file: &str) -> Vec<(u8, u64)> {
+        self.files.get(file).map_or_else(Vec::new, |f| f.line_hits().into_iter().collect())
+    }
+
+    pub fn write_lcov(&self, out: &mut impl Write) -> io::Result<()> {
+        for (name, file) in &self.files {
+            let lines = file.line_hits();
+            writeln!(out, "TN:")?;
+            writeln!(out, "SF:{name}")?;
+            for (line, hits) in &lines {
+                writeln!(out, "DA:{line},{hits}")?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(out, "LH:{}", lines.values().filter(|&&h| h > 0).count())?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+
+    // the source with a count beside each line: `-` for no code, `#####` for never run
+    pub fn annotate(&self, file: &str, source: &str) -> String {
+        let lines = self.files.get(file).map(FileCoverage::line_hits).unwrap_or_default();
+        let mut out = String::new();
+        for (i, text) in source.lines().enumerate() {
+            let count = u8::try_from(i + 1).ok().and_then(|l| lines.get(&l));
+            let column = match count {
+                None => "-".to_string(),
+                Some(0) => "#####".to_string(),
+                Some(n) => n.to_string(),
+            };
+            let _ = writeln!(out, "{column:>8}: {:>4}: {text}", i + 1);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InterpretResult, OpCode, Value, VirtualMachine};
+
+    // line 1 pushes the condition, line 2 only runs when it is non-zero
+    fn branchy(condition: i64) -> Chunk {
+        let mut b = Chunk::builder();
+        b.emit_constant(Value::Int(condition));
+        let skip = b.emit_jump_if_false();
+        b.set_line(2).emit_constant(Value::Int(7));
+        b.patch(skip);
+        b.set_line(4).emit_op(OpCode::OpReturn);
+        b.finish().unwrap()
+    }
+
+    fn covered(condition: i64) -> Coverage {
+        let mut vm = VirtualMachine::init_machine();
+        vm.set_coverage(Coverage::new("branchy.lox"));
+        assert_eq!(vm.interpret(branchy(condition)), InterpretResult::InterpretSuccess);
+        vm.take_coverage().unwrap()
+    }
+
+    #[test]
+    fn marks_offsets_and_lines() {
+        let cov = covered(0);
+        assert_eq!(cov.offset_hits("branchy.lox"), vec![(0, 1), (2, 1), (5, 0), (7, 1)]);
+        assert_eq!(cov.line_hits("branchy.lox"), vec![(1, 1), (2, 0), (4, 1)]);
+
+        let annotated = cov.annotate("branchy.lox", "if (x)\n  y\n\nreturn\n");
+        let lines: Vec<&str> = annotated.lines().collect();
+        assert_eq!(lines[0], "       1:    1: if (x)");
+        assert_eq!(lines[1], "   #####:    2:   y");
+        assert_eq!(lines[2], "       -:    3: ");
+    }
+
+    #[test]
+    fn merges_runs_and_writes_lcov() {
+        let mut cov = covered(0);
+        cov.merge(covered(1));
+        cov.merge(covered(1));
+        assert_eq!(cov.line_hits("branchy.lox"), vec![(1, 3), (2, 2), (4, 3)]);
+
+        let mut out = Vec::new();
+        cov.write_lcov(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(
+            text,
+            "TN:\nSF:branchy.lox\nDA:1,3\nDA:2,2\nDA:4,3\nLF:3\nLH:3\nend_of_record\n"
+        );
+    }
+
+    #[test]
+    fn keeps_files_apart() {
+        let mut vm = VirtualMachine::init_machine();
+        vm.set_coverage(Coverage::new("a.lox"));
+        vm.interpret(branchy(0));
+        let mut cov = vm.take_coverage().unwrap();
+        cov.set_file("b.lox");
+        vm.set_coverage(cov);
+        vm.interpret(branchy(1));
+        let cov = vm.take_coverage().unwrap();
+        assert_eq!(cov.files().collect::<Vec<_>>(), vec!["a.lox", "b.lox"]);
+        assert_eq!(cov.line_hits("a.lox")[1], (2, 0));
+        assert_eq!(cov.line_hits("b.lox")[1], (2, 1));
+    }
+}
@@ -0,0 +1,581 @@
+// Debug Adapter Protocol server over a byte stream (normally stdio),
+// so editors can drive the Debugger on .lox files
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::compiler::{CompileOptions, compile_with};
+use crate::json::{Json, object};
+use crate::output::SharedBuf;
+use crate::{Breakpoint, Debugger, InterpretResult, StopReason, VirtualMachine};
+
+// the VM is single threaded, so DAP sees exactly one thread
+const THREAD_ID: i64 = 1;
+const STACK_REFERENCE: i64 = 1;
+// largest message body accepted; a bigger Content-Length is an error, not
+// an allocation
+const MAX_MESSAGE_BYTES: usize = 1 << 20;
+// fuel a program runs on between checks for a pause request
+const FUEL_SLICE: u64 = 10_000;
+
+// what the reader thread hands over: a message, the end of input or a
+// broken stream
+type Incoming = io::Result<Option<String>>;
+
+// serves DAP requests from input until the client disconnects or input ends.
+// input is read on its own thread, so a pause request arrives while a
+// program is running
+pub fn serve(mut input: impl BufRead + Send + 'static, output: impl Write) -> io::Result<()> {
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || {
+        loop {
+            let message = read_message(&mut input);
+            let last = !matches!(message, Ok(Some(_)));
+            if sender.send(message).is_err() || last {
+                break;
+            }
+        }
+    });
+
+    let mut server = DapServer::new(output, requests);
+    while let Some(message) = server.next_message()? {
+        match Json::parse(&message) {
+            Ok(request) => {
+                if !server.handle(&request)? {
+                    break;
+                }
+            }
+            Err(e) => {
+                let body = object([("category", "stderr".into()), ("output", format!("bad message: {e}\n").into())]);
+                server.event("output", body)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// reads one `Content-Length` framed message, None at end of input
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = length.unwrap_or(0);
+    if length > MAX_MESSAGE_BYTES {
+        let message = format!("message of {length} bytes is over the {MAX_MESSAGE_BYTES} byte limit");
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+struct Session {
+    debugger: Debugger,
+    path: String,
+    // what the script prints; stdout carries the protocol, so it is sent
+    // to the client as output events instead
+    printed: SharedBuf,
+}
+
+struct DapServer<W: Write> {
+    out: W,
+    requests: Receiver<Incoming>,
+    // messages that arrived while a program ran, in order
+    pending: VecDeque<Incoming>,
+    fuel_slice: u64,
+    seq: i64,
+    session: Option<Session>,
+    // requested breakpoint lines, applied again whenever a program is launched
+    lines: Vec<i64>,
+    stop_on_entry: bool,
+    configured: bool,
+    started: bool,
+}
+
+impl<W: Write> DapServer<W> {
+    fn new(out: W, requests: Receiver<Incoming>) -> Self {
+        DapServer {
+            out,
+            requests,
+            pending: VecDeque::new(),
+            fuel_slice: FUEL_SLICE,
+            seq: 0,
+            session: None,
+            lines: Vec::new(),
+            stop_on_entry: false,
+            configured: false,
+            started: false,
+        }
+    }
+
+    // handles one request; false once the client asked to disconnect
+    fn handle(&mut self, request: &Json) -> io::Result<bool> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let args = request.get("arguments").cloned().unwrap_or(Json::Null);
+
+        match command {
+            "initialize" => {
+                let body = object([
+                    ("supportsConfigurationDoneRequest", true.into()),
+                    ("supportsSteppingGranularity", true.into()),
+                ]);
+                self.respond(request, Ok(body))?;
+                self.event("initialized", Json::Null)?;
+            }
+            "launch" => {
+                let result = self.launch(&args);
+                let ok = result.is_ok();
+                self.respond(request, result.map(|_| Json::Null))?;
+                if ok {
+                    self.start_if_ready()?;
+                }
+            }
+            "setBreakpoints" => {
+                let body = self.set_breakpoints(&args);
+                self.respond(request, Ok(body))?;
+            }
+            "configurationDone" => {
+                self.configured = true;
+                self.respond(request, Ok(Json::Null))?;
+                self.start_if_ready()?;
+            }
+            "threads" => {
+                let thread = object([("id", THREAD_ID.into()), ("name", "main".into())]);
+                self.respond(request, Ok(object([("threads", vec![thread].into())])))?;
+            }
+            "stackTrace" => {
+                let body = self.stack_trace();
+                self.respond(request, Ok(body))?;
+            }
+            "scopes" => {
+                let scope = object([
+                    ("name", "Stack".into()),
+                    ("variablesReference", STACK_REFERENCE.into()),
+                    ("expensive", false.into()),
+                ]);
+                self.respond(request, Ok(object([("scopes", vec![scope].into())])))?;
+            }
+            "variables" => {
+                let body = self.variables(&args);
+                self.respond(request, Ok(body))?;
+            }
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                if self.session.is_none() {
+                    self.respond(request, Err("no program is running".to_string()))?;
+                    return Ok(true);
+                }
+                let body = match command {
+                    "continue" => object([("allThreadsContinued", true.into())]),
+                    _ => Json::Null,
+                };
+                self.respond(request, Ok(body))?;
+                let instruction = args.get("granularity").and_then(Json::as_str) == Some("instruction");
+                let reason = match command {
+                    "continue" | "stepOut" => self.run(Debugger::resume)?,
+                    _ if instruction => self.run(Debugger::step_instruction)?,
+                    _ => self.run(Debugger::step_line)?,
+                };
+                self.report_stop(reason, "step")?;
+            }
+            "pause" => {
+                // a pause sent while a program runs is answered by run; any
+                // other time the program is already paused
+                self.respond(request, Ok(Json::Null))?;
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Json::Null))?;
+                return Ok(false);
+            }
+            _ => {
+                self.respond(request, Err(format!("unsupported request '{command}'")))?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<(), String> {
+        let path = args
+            .get("program")
+            .and_then(Json::as_str)
+            .ok_or("launch needs a 'program' path")?
+            .to_string();
+        let source = fs::read_to_string(&path).map_err(|e| format!("cannot read {path}: {e}"))?;
+        // unfolded, so every source line keeps instructions to stop on
+        let chunk = compile_with(&source, CompileOptions::unoptimized()).map_err(|e| e.to_string())?;
+
+        self.stop_on_entry = args.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+        let printed = SharedBuf::default();
+        let vm = VirtualMachine::builder().output(Box::new(printed.clone())).fuel(self.fuel_slice).build();
+        self.session = Some(Session {
+            debugger: Debugger::with_machine(vm, chunk),
+            path,
+            printed,
+        });
+        self.apply_breakpoints();
+        // a new program runs once configuration is done, even if an
+        // earlier one already started
+        self.started = false;
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, args: &Json) -> Json {
+        self.lines = args
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|b| b.get("line").and_then(Json::as_i64))
+            .collect();
+        self.apply_breakpoints();
+
+        let breakpoints = self
+            .lines
+            .iter()
+            .map(|&line| {
+                // before launch there is no chunk to check the line against
+                let verified = match &self.session {
+                    Some(s) => breakpoint_line(line).is_some_and(|l| s.debugger.has_code_on_line(l)),
+                    None => true,
+                };
+                object([("verified", verified.into()), ("line", line.into())])
+            })
+            .collect::<Vec<_>>();
+        object([("breakpoints", breakpoints.into())])
+    }
+
+    fn apply_breakpoints(&mut self) {
+        let Some(session) = self.session.as_mut() else {
+            return;
+        };
+        let debugger = &mut session.debugger;
+        debugger.clear_breakpoints();
+        for line in self.lines.iter().copied().filter_map(breakpoint_line) {
+            debugger.add_breakpoint(Breakpoint::Line(line));
+        }
+    }
+
+    // runs once both launch and configurationDone have arrived
+    fn start_if_ready(&mut self) -> io::Result<()> {
+        if self.started || !self.configured || self.session.is_none() {
+            return Ok(());
+        }
+        self.started = true;
+        if self.stop_on_entry {
+            return self.event("stopped", stopped_body("entry"));
+        }
+        let reason = self.run(Debugger::resume)?;
+        self.report_stop(reason, "step")
+    }
+
+    // the next message to handle, None once input has ended
+    fn next_message(&mut self) -> Incoming {
+        match self.pending.pop_front() {
+            Some(message) => message,
+            None => self.requests.recv().unwrap_or(Ok(None)),
+        }
+    }
+
+    // runs a debugger command one fuel slice at a time. between slices it
+    // looks at what the client sent: a pause request stops the run, anything
+    // else waits in pending until the command is done
+    fn run(&mut self, command: fn(&mut Debugger) -> StopReason) -> io::Result<StopReason> {
+        loop {
+            let debugger = &mut self.session.as_mut().expect("a program is loaded").debugger;
+            let reason = command(debugger);
+            if reason != StopReason::OutOfFuel {
+                return Ok(reason);
+            }
+            while let Ok(message) = self.requests.try_recv() {
+                let request = message.as_ref().ok().and_then(Option::as_ref).and_then(|m| Json::parse(m).ok());
+                if let Some(request) = request
+                    && request.get("command").and_then(Json::as_str) == Some("pause")
+                {
+                    self.respond(&request, Ok(Json::Null))?;
+                    return Ok(StopReason::Interrupted);
+                }
+                self.pending.push_back(message);
+            }
+            let debugger = &mut self.session.as_mut().expect("a program is loaded").debugger;
+            debugger.add_fuel(self.fuel_slice);
+        }
+    }
+
+    fn report_stop(&mut self, reason: StopReason, step: &str) -> io::Result<()> {
+        let printed = self.session.as_ref().map(|s| s.printed.take()).unwrap_or_default();
+        if !printed.is_empty() {
+            let text = String::from_utf8_lossy(&printed).into_owned();
+            self.event("output", object([("category", "stdout".into()), ("output", text.into())]))?;
+        }
+        match reason {
+            StopReason::Breakpoint(_) => self.event("stopped", stopped_body("breakpoint")),
+            StopReason::Step => self.event("stopped", stopped_body(step)),
+            StopReason::OutOfFuel | StopReason::Interrupted => self.event("stopped", stopped_body("pause")),
+            StopReason::Finished(result) => {
+                let stack = self
+                    .session
+                    .as_ref()
+                    .map(|s| format!("{:?}", s.debugger.vm().stack))
+                    .unwrap_or_default();
+                let text = format!("{result:?} {stack}\n");
+                self.event("output", object([("category", "console".into()), ("output", text.into())]))?;
+                let code: i64 = if result == InterpretResult::InterpretSuccess { 0 } else { 70 };
+                self.event("exited", object([("exitCode", code.into())]))?;
+                self.event("terminated", Json::Null)
+            }
+        }
+    }
+
+    fn stack_trace(&self) -> Json {
+        let Some(session) = &self.session else {
+            return object([("stackFrames", Json::Array(Vec::new())), ("totalFrames", 0i64.into())]);
+        };
+        let debugger = &session.debugger;
+        let name = session.path.rsplit(['/', '\\']).next().unwrap_or(&session.path);
+        let frame = object([
+            ("id", 0i64.into()),
+            ("name", "script".into()),
+            ("line", i64::from(debugger.current_line().unwrap_or(0)).into()),
+            ("column", 1i64.into()),
+            ("instructionPointerReference", debugger.current_offset().to_string().into()),
+            ("source", object([("name", name.into()), ("path", session.path.as_str().into())])),
+        ]);
+        object([("stackFrames", vec![frame].into()), ("totalFrames", 1i64.into())])
+    }
+
+    // stack slots are the only variables the VM has
+    fn variables(&self, args: &Json) -> Json {
+        let reference = args.get("variablesReference").and_then(Json::as_i64);
+        let variables = match (&self.session, reference) {
+            (Some(session), Some(STACK_REFERENCE)) => session
+                .debugger
+                .vm()
+                .stack
+                .iter()
+                .enumerate()
+                .map(|(slot, value)| {
+                    object([
+                        ("name", format!("[{slot}]").into()),
+                        ("value", value.to_string().into()),
+                        ("variablesReference", 0i64.into()),
+                    ])
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        object([("variables", variables.into())])
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+        let request_seq = request.get("seq").cloned().unwrap_or(Json::Null);
+        let command = request.get("command").cloned().unwrap_or(Json::Null);
+        let mut fields = vec![
+            ("type".to_string(), Json::from("response")),
+            ("request_seq".to_string(), request_seq),
+            ("command".to_string(), command),
+        ];
+        match result {
+            Ok(body) => {
+                fields.push(("success".to_string(), true.into()));
+                if body != Json::Null {
+                    fields.push(("body".to_string(), body));
+                }
+            }
+            Err(message) => {
+                fields.push(("success".to_string(), false.into()));
+                fields.push(("message".to_string(), message.into()));
+            }
+        }
+        self.send(fields)
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        let mut fields = vec![
+            ("type".to_string(), Json::from("event")),
+            ("event".to_string(), event.into()),
+        ];
+        if body != Json::Null {
+            fields.push(("body".to_string(), body));
+        }
+        self.send(fields)
+    }
+
+    fn send(&mut self, mut fields: Vec<(String, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        fields.insert(0, ("seq".to_string(), self.seq.into()));
+        let text = Json::Object(fields).to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", text.len(), text)?;
+        self.out.flush()
+    }
+}
+
+fn stopped_body(reason: &str) -> Json {
+    object([
+        ("reason", reason.into()),
+        ("threadId", THREAD_ID.into()),
+        ("allThreadsStopped", true.into()),
+    ])
+}
+
+fn breakpoint_line(line: i64) -> Option<u8> {
+    u8::try_from(line).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(body: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    fn messages(output: &[u8]) -> Vec<Json> {
+        let mut input = output;
+        let mut out = Vec::new();
+        while let Some(text) = read_message(&mut input).unwrap() {
+            out.push(Json::parse(&text).unwrap());
+        }
+        out
+    }
+
+    #[test]
+    fn oversized_messages_are_refused() {
+        let header = format!("Content-Length: {}\r\n\r\n", usize::MAX);
+        let error = read_message(&mut header.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let body = "x".repeat(MAX_MESSAGE_BYTES + 1);
+        assert!(serve(io::Cursor::new(frame(&body)), Vec::new()).is_err());
+        let body = format!("\"{}\"", "x".repeat(MAX_MESSAGE_BYTES - 2));
+        assert!(serve(io::Cursor::new(frame(&body)), Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn pause_stops_a_running_program() {
+        let path = std::env::temp_dir().join(format!("dap_pause_{}.lox", std::process::id()));
+        fs::write(&path, "print 1;\nprint 2;\nprint 3;").unwrap();
+        let program = path.to_str().unwrap().replace('\\', "\\\\");
+
+        // the pause is already waiting when the program starts, and is
+        // seen after its first slice of two instructions
+        let (sender, requests) = mpsc::channel();
+        let mut server = DapServer::new(Vec::new(), requests);
+        server.fuel_slice = 2;
+        sender.send(Ok(Some(r#"{"seq":4,"type":"request","command":"pause","arguments":{"threadId":1}}"#.to_string()))).unwrap();
+        let launch = format!(r#"{{"seq":1,"type":"request","command":"launch","arguments":{{"program":"{program}"}}}}"#);
+        for request in [launch.as_str(), r#"{"seq":2,"type":"request","command":"configurationDone"}"#] {
+            assert!(server.handle(&Json::parse(request).unwrap()).unwrap());
+        }
+        fs::remove_file(&path).unwrap();
+
+        let sent = messages(&std::mem::take(&mut server.out));
+        let pause = sent.iter().find(|m| m.get("request_seq").and_then(Json::as_i64) == Some(4)).unwrap();
+        assert_eq!(pause.get("success").and_then(Json::as_bool), Some(true));
+        let stopped = sent.last().unwrap();
+        assert_eq!(stopped.get("event").and_then(Json::as_str), Some("stopped"));
+        assert_eq!(stopped.get("body").unwrap().get("reason").and_then(Json::as_str), Some("pause"));
+        assert!(sent.iter().any(|m| m.get("body").and_then(|b| b.get("output")).and_then(Json::as_str) == Some("1\n")));
+
+        // continuing with nothing queued runs to the end
+        let resume = r#"{"seq":5,"type":"request","command":"continue","arguments":{"threadId":1}}"#;
+        assert!(server.handle(&Json::parse(resume).unwrap()).unwrap());
+        let sent = messages(&server.out);
+        assert_eq!(sent.last().unwrap().get("event").and_then(Json::as_str), Some("terminated"));
+    }
+
+    #[test]
+    fn second_launch_runs_again() {
+        let path = std::env::temp_dir().join(format!("dap_relaunch_{}.lox", std::process::id()));
+        fs::write(&path, "print 1 + 2;").unwrap();
+        let program = path.to_str().unwrap().replace('\\', "\\\\");
+        let launch = |seq: i64| {
+            frame(&format!(r#"{{"seq":{seq},"type":"request","command":"launch","arguments":{{"program":"{program}"}}}}"#))
+        };
+        let input = [
+            frame(r#"{"seq":1,"type":"request","command":"initialize","arguments":{}}"#),
+            launch(2),
+            frame(r#"{"seq":3,"type":"request","command":"configurationDone"}"#),
+            launch(4),
+        ]
+        .concat();
+
+        let mut output = Vec::new();
+        serve(io::Cursor::new(input), &mut output).unwrap();
+        fs::remove_file(&path).unwrap();
+        let messages = messages(&output);
+        let events = |name: &str| messages.iter().filter(|m| m.get("event").and_then(Json::as_str) == Some(name)).count();
+        assert_eq!(events("terminated"), 2);
+    }
+
+    #[test]
+    fn breakpoint_session() {
+        let path = std::env::temp_dir().join(format!("dap_unit_{}.lox", std::process::id()));
+        fs::write(&path, "(1 +\n2) *\n3").unwrap();
+        let program = path.to_str().unwrap().replace('\\', "\\\\");
+
+        let input = [
+            frame(r#"{"seq":1,"type":"request","command":"initialize","arguments":{}}"#),
+            frame(&format!(r#"{{"seq":2,"type":"request","command":"launch","arguments":{{"program":"{program}"}}}}"#)),
+            frame(&format!(r#"{{"seq":3,"type":"request","command":"setBreakpoints","arguments":{{"source":{{"path":"{program}"}},"breakpoints":[{{"line":2}},{{"line":9}}]}}}}"#)),
+            frame(r#"{"seq":4,"type":"request","command":"configurationDone"}"#),
+            frame(r#"{"seq":5,"type":"request","command":"stackTrace","arguments":{"threadId":1}}"#),
+            frame(r#"{"seq":6,"type":"request","command":"variables","arguments":{"variablesReference":1}}"#),
+            frame(r#"{"seq":7,"type":"request","command":"next","arguments":{"threadId":1}}"#),
+            frame(r#"{"seq":8,"type":"request","command":"continue","arguments":{"threadId":1}}"#),
+            frame(r#"{"seq":9,"type":"request","command":"continue","arguments":{"threadId":1}}"#),
+            frame(r#"{"seq":10,"type":"request","command":"disconnect"}"#),
+        ]
+        .concat();
+
+        let mut output = Vec::new();
+        serve(io::Cursor::new(input), &mut output).unwrap();
+        fs::remove_file(&path).unwrap();
+        let messages = messages(&output);
+
+        let event = |name: &str| messages.iter().filter(|m| m.get("event").and_then(Json::as_str) == Some(name)).count();
+        let response = |seq: i64| {
+            messages
+                .iter()
+                .find(|m| m.get("request_seq").and_then(Json::as_i64) == Some(seq))
+                .unwrap()
+        };
+
+        assert_eq!(event("initialized"), 1);
+        let verified: Vec<_> = response(3).get("body").unwrap().get("breakpoints").unwrap().as_array().unwrap()
+            .iter()
+            .map(|b| b.get("verified").and_then(Json::as_bool).unwrap())
+            .collect();
+        assert_eq!(verified, vec![true, false]);
+
+        let frames = response(5).get("body").unwrap().get("stackFrames").unwrap().as_array().unwrap().to_vec();
+        assert_eq!(frames[0].get("line").and_then(Json::as_i64), Some(2));
+
+        let variables = response(6).get("body").unwrap().get("variables").unwrap().as_array().unwrap().to_vec();
+        assert_eq!(variables.len(), 1);
+        assert_eq!(variables[0].get("value").and_then(Json::as_str), Some("1"));
+
+        // entry breakpoint, next, the second visit to line 2, then the end
+        assert_eq!(event("stopped"), 3);
+        assert_eq!(event("terminated"), 1);
+        assert!(response(10).get("success").and_then(Json::as_bool).unwrap());
+    }
+}
@@ -0,0 +1,428 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use crate::{Chunk, InterpretResult, StepResult, VirtualMachine};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Breakpoint {
+    // pause before the first instruction of a source line
+    Line(u8),
+    // pause before the instruction at a bytecode offset
+    Offset(usize),
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Breakpoint::Line(line) => write!(f, "line {line}"),
+            Breakpoint::Offset(offset) => write!(f, "offset {offset:04}"),
+        }
+    }
+}
+
+// why the debugger handed control back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint(Breakpoint),
+    Step,
+    // the fuel ran out before the next instruction; add_fuel and resume
+    OutOfFuel,
+    // the VM's interrupt handle stopped it at a backward jump; resume
+    // carries on from there
+    Interrupted,
+    Finished(InterpretResult),
+}
+
+// drives a VirtualMachine one instruction at a time so execution can be
+// paused between any two instructions. each step goes through run_for, so
+// the machine's fuel, trace, profiler and coverage see every instruction
+pub struct Debugger {
+    vm: VirtualMachine,
+    breakpoints: Vec<Breakpoint>,
+    // line of the last executed instruction, for line breakpoints
+    last_line: Option<u8>,
+    // false until the first command, so a breakpoint on the entry instruction can hit
+    entered: bool,
+    finished: Option<InterpretResult>,
+}
+
+impl Debugger {
+    pub fn new(chunk: Chunk) -> Self {
+        Debugger::with_machine(VirtualMachine::init_machine(), chunk)
+    }
+
+    pub fn with_machine(mut vm: VirtualMachine, chunk: Chunk) -> Self {
+        vm.load(chunk);
+        Debugger {
+            vm,
+            breakpoints: Vec::new(),
+            last_line: None,
+            entered: false,
+            finished: None,
+        }
+    }
+
+    pub fn vm(&self) -> &VirtualMachine {
+        &self.vm
+    }
+
+    pub fn into_machine(self) -> VirtualMachine {
+        self.vm
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+        let before = self.breakpoints.len();
+        self.breakpoints.retain(|b| *b != breakpoint);
+        before != self.breakpoints.len()
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn finished(&self) -> Option<InterpretResult> {
+        self.finished
+    }
+
+    // tops up the machine's fuel so a run stopped by OutOfFuel can go on
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.vm.add_fuel(fuel);
+    }
+
+    // offset of the next instruction to execute
+    pub fn current_offset(&self) -> usize {
+        self.vm.ip
+    }
+
+    pub fn current_line(&self) -> Option<u8> {
+        self.vm.chunk.as_ref()?.line_at(self.vm.ip)
+    }
+
+    // whether a line breakpoint there could ever be hit
+    pub fn has_code_on_line(&self, line: u8) -> bool {
+        self.vm.chunk.as_ref().is_some_and(|c| c.first_offset_of_line(line).is_some())
+    }
+
+    pub fn current_instruction(&self) -> Option<String> {
+        let chunk = self.vm.chunk.as_ref()?;
+        (self.vm.ip < chunk.len()).then(|| chunk.describe_instruction(self.vm.ip).0)
+    }
+
+    pub fn step_instruction(&mut self) -> StopReason {
+        self.run_until(|_| true)
+    }
+
+    // runs until execution reaches a different source line
+    pub fn step_line(&mut self) -> StopReason {
+        let start = self.current_line();
+        self.run_until(|d| d.current_line() != start)
+    }
+
+    // runs until a breakpoint is hit or the program ends
+    pub fn resume(&mut self) -> StopReason {
+        self.run_until(|_| false)
+    }
+
+    // always executes at least one instruction, so resuming from a
+    // breakpoint does not immediately stop on it again
+    fn run_until(&mut self, mut stop: impl FnMut(&Debugger) -> bool) -> StopReason {
+        if !self.entered {
+            self.entered = true;
+            if let Some(breakpoint) = self.breakpoint_hit() {
+                return StopReason::Breakpoint(breakpoint);
+            }
+        }
+        loop {
+            if let Some(result) = self.finished {
+                return StopReason::Finished(result);
+            }
+
+            self.last_line = self.current_line();
+            // running out of fuel and being interrupted leave the machine
+            // where it can go on, so they pause rather than finish
+            let result = match self.vm.step() {
+                StepResult::Paused => None,
+                StepResult::OutOfFuel => return StopReason::OutOfFuel,
+                StepResult::Interrupted(_) => return StopReason::Interrupted,
+                StepResult::Finished(_) => Some(InterpretResult::InterpretSuccess),
+                StepResult::Error(_) => Some(InterpretResult::InterpretRuntimeError),
+            };
+            if let Some(result) = result {
+                self.finished = Some(result);
+                return StopReason::Finished(result);
+            }
+
+            if let Some(breakpoint) = self.breakpoint_hit() {
+                return StopReason::Breakpoint(breakpoint);
+            }
+            if stop(self) {
+                return StopReason::Step;
+            }
+        }
+    }
+
+    fn breakpoint_hit(&self) -> Option<Breakpoint> {
+        let offset = self.vm.ip;
+        let line = self.current_line();
+        self.breakpoints.iter().copied().find(|b| match *b {
+            Breakpoint::Offset(o) => o == offset,
+            Breakpoint::Line(l) => line == Some(l) && self.last_line != Some(l),
+        })
+    }
+
+    // a line-oriented front end: reads commands from input until the
+    // program finishes or the user quits
+    pub fn run_repl(&mut self, input: impl BufRead, out: &mut impl Write) -> io::Result<Option<InterpretResult>> {
+        writeln!(out, "type 'help' for commands")?;
+        self.show_position(out)?;
+        for line in input.lines() {
+            let line = line?;
+            let mut words = line.split_whitespace();
+            let Some(command) = words.next() else {
+                continue;
+            };
+            let arg = words.next();
+
+            let reason = match command {
+                "s" | "step" => Some(self.step_instruction()),
+                "n" | "next" => Some(self.step_line()),
+                "c" | "continue" => Some(self.resume()),
+                "f" | "fuel" => {
+                    match arg.and_then(|a| a.parse().ok()) {
+                        Some(fuel) => {
+                            self.add_fuel(fuel);
+                            writeln!(out, "fuel left: {}", self.vm.fuel().unwrap_or(fuel))?;
+                        }
+                        None => writeln!(out, "usage: fuel <units>")?,
+                    }
+                    None
+                }
+                "b" | "break" => {
+                    match arg.and_then(parse_breakpoint) {
+                        Some(b) => {
+                            self.add_breakpoint(b);
+                            writeln!(out, "breakpoint set at {b}")?;
+                        }
+                        None => writeln!(out, "usage: break <line> | break @<offset>")?,
+                    }
+                    None
+                }
+                "d" | "delete" => {
+                    match arg {
+                        Some("all") | None => self.clear_breakpoints(),
+                        Some(a) => match parse_breakpoint(a) {
+                            Some(b) if self.remove_breakpoint(b) => writeln!(out, "deleted {b}")?,
+                            _ => writeln!(out, "no breakpoint at {a}")?,
+                        },
+                    }
+                    None
+                }
+                "bl" | "breakpoints" => {
+                    for b in &self.breakpoints {
+                        writeln!(out, "  {b}")?;
+                    }
+                    None
+                }
+                "p" | "stack" => {
+                    self.show_stack(out)?;
+                    None
+                }
+                "w" | "where" => {
+                    self.show_position(out)?;
+                    None
+                }
+                "l" | "list" => {
+                    self.show_listing(out)?;
+                    None
+                }
+                "q" | "quit" => return Ok(self.finished),
+                "h" | "help" => {
+                    writeln!(out, "{HELP}")?;
+                    None
+                }
+                other => {
+                    writeln!(out, "unknown command '{other}', type 'help' for commands")?;
+                    None
+                }
+            };
+
+            match reason {
+                Some(StopReason::Finished(result)) => {
+                    self.show_stack(out)?;
+                    writeln!(out, "program finished: {result:?}")?;
+                    return Ok(Some(result));
+                }
+                Some(StopReason::Breakpoint(b)) => {
+                    writeln!(out, "hit breakpoint at {b}")?;
+                    self.show_position(out)?;
+                }
+                Some(StopReason::Step) => self.show_position(out)?,
+                Some(StopReason::OutOfFuel) => {
+                    writeln!(out, "out of fuel, 'fuel <units>' adds more")?;
+                    self.show_position(out)?;
+                }
+                Some(StopReason::Interrupted) => {
+                    writeln!(out, "interrupted")?;
+                    self.show_position(out)?;
+                }
+                None => {}
+            }
+        }
+        Ok(self.finished)
+    }
+
+    fn show_position(&self, out: &mut impl Write) -> io::Result<()> {
+        self.show_stack(out)?;
+        match self.current_instruction() {
+            Some(text) => writeln!(out, "-> {text}"),
+            None => writeln!(out, "-> (end of chunk)"),
+        }
+    }
+
+    // locals live in stack slots, so the slot index is shown with each value
+    fn show_stack(&self, out: &mut impl Write) -> io::Result<()> {
+        write!(out, "stack:")?;
+        if self.vm.stack.is_empty() {
+            write!(out, " (empty)")?;
+        }
+        for (slot, value) in self.vm.stack.iter().enumerate() {
+            write!(out, " [{slot}] {value}")?;
+        }
+        writeln!(out)
+    }
+
+    fn show_listing(&self, out: &mut impl Write) -> io::Result<()> {
+        let Some(chunk) = self.vm.chunk.as_ref() else {
+            return Ok(());
+        };
+        let mut offset = 0;
+        while offset < chunk.len() {
+            let (text, next) = chunk.describe_instruction(offset);
+            let marker = if offset == self.vm.ip { "->" } else { "  " };
+            writeln!(out, "{marker} {text}")?;
+            offset = next;
+        }
+        Ok(())
+    }
+}
+
+const HELP: &str = "\
+  s, step            execute one instruction
+  n, next            run to the next source line
+  c, continue        run to the next breakpoint
+  f, fuel <units>    add fuel after the program ran out
+  b, break <line>    break at a source line (break @<offset> for bytecode)
+  d, delete [bp]     delete one breakpoint, or all of them
+  bl, breakpoints    list breakpoints
+  p, stack           show the stack
+  w, where           show the stack and next instruction
+  l, list            disassemble the chunk
+  q, quit            leave the debugger";
+
+fn parse_breakpoint(arg: &str) -> Option<Breakpoint> {
+    match arg.strip_prefix('@') {
+        Some(offset) => offset.parse().ok().map(Breakpoint::Offset),
+        None => arg.parse().ok().map(Breakpoint::Line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Coverage, Profiler, Value};
+    use crate::compiler::{CompileOptions, compile_with};
+
+    // folding would merge the lines these tests step through
+    fn compile(source: &str) -> Result<crate::Chunk, crate::CompileError> {
+        compile_with(source, CompileOptions::unoptimized())
+    }
+
+    #[test]
+    fn steps_and_breakpoints() {
+        // line 1: 1 +   line 2: 2 *   line 3: 3
+        let chunk = compile("1 +\n2 *\n3").unwrap();
+        let mut d = Debugger::new(chunk);
+
+        assert_eq!(d.current_line(), Some(1));
+        assert_eq!(d.step_instruction(), StopReason::Step);
+        assert_eq!(d.current_offset(), 2);
+        assert_eq!(d.current_line(), Some(2));
+        assert_eq!(d.step_line(), StopReason::Step);
+        assert_eq!(d.current_line(), Some(3));
+        assert_eq!(d.vm().stack, vec![Value::Int(1), Value::Int(2)]);
+
+        d.add_breakpoint(Breakpoint::Offset(6));
+        assert_eq!(d.resume(), StopReason::Breakpoint(Breakpoint::Offset(6)));
+        assert_eq!(d.vm().stack, vec![Value::Int(1), Value::Int(2), Value::Int(3)]);
+
+        assert_eq!(d.resume(), StopReason::Finished(InterpretResult::InterpretSuccess));
+        assert_eq!(d.vm().stack, vec![Value::Int(7)]);
+        assert_eq!(d.step_instruction(), StopReason::Finished(InterpretResult::InterpretSuccess));
+    }
+
+    #[test]
+    fn steps_are_instrumented() {
+        let vm = VirtualMachine::builder().fuel(3).profiler(Profiler::new()).coverage(Coverage::new("steps.lox")).build();
+        let mut d = Debugger::with_machine(vm, compile("1 + 2 * 3").unwrap());
+        assert_eq!(d.step_instruction(), StopReason::Step);
+        assert_eq!(d.vm().fuel(), Some(2));
+        assert_eq!(d.resume(), StopReason::OutOfFuel);
+        assert_eq!(d.current_offset(), 6);
+        assert_eq!(d.finished(), None);
+        assert_eq!(d.vm().profiler().unwrap().total_instructions(), 3);
+        assert_eq!(d.vm().coverage().unwrap().offset_hits("steps.lox")[..4], [(0, 1), (2, 1), (4, 1), (6, 0)]);
+
+        // more fuel picks up where it stopped
+        assert_eq!(d.resume(), StopReason::OutOfFuel);
+        d.add_fuel(10);
+        assert_eq!(d.resume(), StopReason::Finished(InterpretResult::InterpretSuccess));
+        assert_eq!(d.vm().stack, vec![Value::Int(7)]);
+        assert_eq!(d.vm().profiler().unwrap().total_instructions(), 6);
+    }
+
+    #[test]
+    fn line_breakpoint_stops_once_per_entry() {
+        let chunk = compile("1 +\n2 *\n3").unwrap();
+        let mut d = Debugger::new(chunk);
+        d.add_breakpoint(Breakpoint::Line(3));
+        assert_eq!(d.resume(), StopReason::Breakpoint(Breakpoint::Line(3)));
+        assert_eq!(d.current_offset(), 4);
+        // the OpReturn is on line 3 again after the operators on lines 2 and 1
+        assert_eq!(d.resume(), StopReason::Breakpoint(Breakpoint::Line(3)));
+        assert_eq!(d.current_offset(), 8);
+        assert_eq!(d.resume(), StopReason::Finished(InterpretResult::InterpretSuccess));
+
+        // a breakpoint on the very first instruction stops before it runs
+        let mut d = Debugger::new(compile("1 +\n2").unwrap());
+        d.add_breakpoint(Breakpoint::Line(1));
+        assert_eq!(d.resume(), StopReason::Breakpoint(Breakpoint::Line(1)));
+        assert_eq!(d.current_offset(), 0);
+        assert!(d.has_code_on_line(2));
+        assert!(!d.has_code_on_line(3));
+    }
+
+    #[test]
+    fn repl_drives_the_debugger() {
+        let chunk = compile("(1 +\n2)").unwrap();
+        let mut d = Debugger::new(chunk);
+        let mut out = Vec::new();
+        let result = d.run_repl("break 2\nc\nstack\nc\nc\n".as_bytes(), &mut out).unwrap();
+        assert_eq!(result, Some(InterpretResult::InterpretSuccess));
+
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("breakpoint set at line 2"));
+        assert!(text.contains("hit breakpoint at line 2"));
+        assert!(text.contains("stack: [0] 1\n"));
+        assert!(text.contains("program finished: InterpretSuccess"));
+    }
+}
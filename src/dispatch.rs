@@ -0,0 +1,310 @@
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+
+use crate::{ErrorKind, InterpretResult, Number, OpCode, VirtualMachine};
+
+// the uninstrumented loop for chunks that passed verification. it keeps the
+// code, constants and ip in locals instead of going through self.chunk for
+// every instruction, and it leaves out every check the verifier already
+// made: opcodes decode, operands and constant indices are in range, jumps
+// land on instructions, nothing runs past the end and no instruction pops
+// more than the stack holds. only the limits that depend on how long the
+// script runs, stack size and heap bytes, are checked as it goes
+impl<V: Number> VirtualMachine<V> {
+    // the verifier's stack depths count from an empty stack at offset 0, so
+    // that is the only place this loop may start; resuming after an error,
+    // which can leave the stack shallower than the verifier assumed, goes
+    // through the checked loop instead
+    pub(crate) fn can_run_verified(&self) -> bool {
+        self.verified && self.ip == 0
+    }
+
+    pub(crate) fn run_verified(&mut self) -> InterpretResult {
+        let mut offset = self.ip;
+        match self.dispatch_verified(&mut offset) {
+            Ok(result) => result,
+            Err(kind) => {
+                // like the checked loop, leave ip after the failed instruction;
+                // an interrupt has already moved it to the loop's target
+                let byte = self.chunk.as_ref().map_or(0, |c| c.code[offset]);
+                let op = OpCode::from_byte(byte).expect("the chunk was verified");
+                if !matches!(kind, ErrorKind::Interrupted) {
+                    self.ip = offset + 1 + op.operands().width();
+                }
+                self.fail(kind, offset)
+            }
+        }
+    }
+
+    // runs until OpReturn or an error; offset is left on the instruction that
+    // failed, and ip is only written back when the run stops without one
+    #[inline(never)]
+    fn dispatch_verified(&mut self, offset: &mut usize) -> Result<InterpretResult, ErrorKind<V>> {
+        let VirtualMachine { chunk, ip: saved_ip, stack, heap, config, output, interrupt, .. } = self;
+        let chunk = chunk.as_ref().expect("verified implies loaded");
+        let (code, values) = (&chunk.code[..], &chunk.values[..]);
+        let mode = config.arithmetic;
+
+        let mut ip = *saved_ip;
+        // SAFETY for every unchecked read below: see the comment on the impl
+        macro_rules! byte {
+            ($at:expr) => {
+                *unsafe { code.get_unchecked($at) }
+            };
+        }
+        macro_rules! short {
+            ($at:expr) => {
+                ((byte!($at) as usize) << 8) | byte!($at + 1) as usize
+            };
+        }
+        macro_rules! constant {
+            ($at:expr) => {
+                {
+                    let index = byte!($at) as usize;
+                    unsafe { values.get_unchecked(index) }.clone()
+                }
+            };
+        }
+        macro_rules! pop {
+            () => {{
+                let value = unsafe { stack.pop().unwrap_unchecked() };
+                *heap -= value.heap_size();
+                value
+            }};
+        }
+        macro_rules! push {
+            ($value:expr) => {{
+                let value = $value;
+                if stack.len() >= config.max_stack {
+                    return Err(ErrorKind::StackOverflow);
+                }
+                let size = value.heap_size();
+                if *heap + size > config.max_heap_bytes {
+                    return Err(ErrorKind::OutOfMemory);
+                }
+                *heap += size;
+                stack.push(value);
+            }};
+        }
+        // overwrites the top of the stack, which top borrows, with value;
+        // when value does not fit the old one is popped, as if the checked
+        // loop had popped it and then failed to push
+        macro_rules! replace_top {
+            ($top:ident, $value:expr) => {{
+                let value = $value;
+                let (kept, size) = (*heap - $top.heap_size(), value.heap_size());
+                if kept + size > config.max_heap_bytes {
+                    pop!();
+                    return Err(ErrorKind::OutOfMemory);
+                }
+                *heap = kept + size;
+                *$top = value;
+            }};
+        }
+        // replaces the top of the stack with the result, in place; on an
+        // error both operands are gone, as in the checked loop
+        macro_rules! binary {
+            ($op:expr, $right:expr, |$a:ident, $b:ident| $result:expr) => {{
+                let $b = $right;
+                let $a = unsafe { stack.last_mut().unwrap_unchecked() };
+                match $result {
+                    Ok(v) => replace_top!($a, v),
+                    Err(e) => {
+                        let left = pop!();
+                        return Err(Self::arithmetic_error($op, Some(left), $b, e));
+                    }
+                }
+            }};
+        }
+        macro_rules! unary {
+            ($op:expr, |$a:ident| $result:expr) => {{
+                let $a = unsafe { stack.last_mut().unwrap_unchecked() };
+                match $result {
+                    Ok(v) => replace_top!($a, v),
+                    Err(e) => {
+                        let v = pop!();
+                        return Err(Self::arithmetic_error($op, None, v, e));
+                    }
+                }
+            }};
+        }
+
+        loop {
+            *offset = ip;
+            let byte = byte!(ip);
+            let op = unsafe { OpCode::from_byte(byte).unwrap_unchecked() };
+            ip += 1;
+            match op {
+                OpCode::OpReturn => {
+                    *saved_ip = ip;
+                    return Ok(InterpretResult::InterpretSuccess);
+                }
+                OpCode::OpConstant => {
+                    let value = constant!(ip);
+                    ip += 1;
+                    push!(value);
+                }
+                OpCode::OpNegate => unary!(op, |a| a.neg(mode)),
+                OpCode::OpBitNot => unary!(op, |a| a.bit_not()),
+                OpCode::OpAdd => binary!(op, pop!(), |a, b| a.add(&b, mode)),
+                OpCode::OpSubtract => binary!(op, pop!(), |a, b| a.sub(&b, mode)),
+                OpCode::OpMultiply => binary!(op, pop!(), |a, b| a.mul(&b, mode)),
+                OpCode::OpDivide => binary!(op, pop!(), |a, b| a.div(&b, mode)),
+                OpCode::OpModulo => binary!(op, pop!(), |a, b| a.rem(&b, mode)),
+                OpCode::OpPower => binary!(op, pop!(), |a, b| a.pow(&b, mode)),
+                OpCode::OpBitAnd => binary!(op, pop!(), |a, b| a.bit_and(&b)),
+                OpCode::OpBitOr => binary!(op, pop!(), |a, b| a.bit_or(&b)),
+                OpCode::OpBitXor => binary!(op, pop!(), |a, b| a.bit_xor(&b)),
+                OpCode::OpShl => binary!(op, pop!(), |a, b| a.shl(&b, mode)),
+                OpCode::OpShr => binary!(op, pop!(), |a, b| a.shr(&b)),
+                OpCode::OpAddConstant => {
+                    let right = constant!(ip);
+                    ip += 1;
+                    binary!(OpCode::OpAdd, right, |a, b| a.add(&b, mode));
+                }
+                OpCode::OpSubtractConstant => {
+                    let right = constant!(ip);
+                    ip += 1;
+                    binary!(OpCode::OpSubtract, right, |a, b| a.sub(&b, mode));
+                }
+                OpCode::OpPop => {
+                    pop!();
+                }
+                OpCode::OpDup => {
+                    let top = unsafe { stack.last().unwrap_unchecked() }.clone();
+                    push!(top);
+                }
+                OpCode::OpSwap => {
+                    let len = stack.len();
+                    stack.swap(len - 2, len - 1);
+                }
+                OpCode::OpOver => {
+                    let second = unsafe { stack.get_unchecked(stack.len() - 2) }.clone();
+                    push!(second);
+                }
+                OpCode::OpRot => {
+                    let len = stack.len();
+                    stack[len - 3..].rotate_left(1);
+                }
+                OpCode::OpJump => ip += 2 + short!(ip),
+                OpCode::OpJumpIfFalse => {
+                    let jump = short!(ip);
+                    ip += 2;
+                    if pop!().is_zero() {
+                        ip += jump;
+                    }
+                }
+                OpCode::OpLoop => {
+                    ip = ip + 2 - short!(ip);
+                    if interrupt.load(Ordering::Relaxed) && interrupt.swap(false, Ordering::Relaxed) {
+                        *saved_ip = ip;
+                        return Err(ErrorKind::Interrupted);
+                    }
+                }
+                OpCode::OpPrint => {
+                    let value = pop!();
+                    let written = match output.as_mut() {
+                        Some(sink) => writeln!(sink, "{value}"),
+                        None => writeln!(io::stdout(), "{value}"),
+                    };
+                    if let Err(e) = written {
+                        return Err(ErrorKind::OutputFailed(e.kind()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::{CompileOptions, compile_with};
+    use crate::testing::countdown;
+    use crate::{Arithmetic, Chunk, InterpretResult, OpCode, Value, VirtualMachine, VmBuilder, VmConfig};
+
+    fn machine(config: VmConfig, checked: bool) -> VirtualMachine {
+        let vm = VmBuilder::default().config(config).output(Box::new(std::io::sink()));
+        // any fuel budget sends run() through the checked loop
+        if checked { vm.fuel(u64::MAX).build() } else { vm.build() }
+    }
+
+    // runs chunk with the verified and the checked loop, on a stack that
+    // already holds `below`, and compares everything the run left behind
+    fn same_as_checked(chunk: &Chunk, config: VmConfig, below: &[Value]) -> InterpretResult {
+        let [verified, checked] = [false, true].map(|checked| {
+            let mut vm = machine(config, checked);
+            vm.stack.extend_from_slice(below);
+            vm.load(chunk.clone());
+            assert!(vm.can_run_verified());
+            let result = vm.run();
+            (result, format!("{:?} {} {:?}", vm.stack(), vm.ip(), vm.runtime_error()))
+        });
+        assert_eq!(verified, checked);
+        verified.0
+    }
+
+    #[test]
+    fn verified_loop_matches_checked_loop() {
+        let sources = [
+            "1 + 2 * 3 - 4 / 5 % 6",
+            "-(2 ** 70) >> 3 & ~5 | 6 ^ 7 << 2",
+            "9223372036854775807 + 1 - 1.5",
+            "print 1; print 2 ** 0.5;\n 7 / 0",
+            "1 << -1",
+            "2.5 & 1",
+        ];
+        for source in sources {
+            let chunk = compile_with(source, CompileOptions::unoptimized()).unwrap();
+            for arithmetic in [Arithmetic::Wrapping, Arithmetic::Checked] {
+                let config = VmConfig { arithmetic, ..VmConfig::default() };
+                same_as_checked(&chunk, config, &[]);
+                same_as_checked(&chunk, config, &[Value::Int(7), Value::Float(0.5)]);
+            }
+        }
+
+        let chunk = countdown(Value::Int(300), Value::Int(1));
+        assert_eq!(same_as_checked(&chunk, VmConfig::default(), &[]), InterpretResult::InterpretSuccess);
+        // and with the step fused into OpSubtractConstant
+        let mut optimized = chunk.clone();
+        optimized.optimize(Arithmetic::Wrapping).unwrap();
+        assert_eq!(same_as_checked(&optimized, VmConfig::default(), &[]), InterpretResult::InterpretSuccess);
+        // the limits still hold, and name the one that was hit
+        let small = VmConfig { max_stack: 2, ..VmConfig::default() };
+        assert_eq!(same_as_checked(&chunk, small, &[Value::Int(0)]), InterpretResult::InterpretRuntimeError);
+        // big integers own heap bytes, through pushes and in-place results alike
+        let chunk = compile_with("2 ** 100 * 2 ** 100 + -(2 ** 64 + 1)", CompileOptions::unoptimized()).unwrap();
+        let results: Vec<InterpretResult> = (0..400)
+            .step_by(4)
+            .map(|max_heap_bytes| same_as_checked(&chunk, VmConfig { max_heap_bytes, ..VmConfig::default() }, &[]))
+            .collect();
+        assert!(results.contains(&InterpretResult::InterpretRuntimeError));
+        assert_eq!(results.last(), Some(&InterpretResult::InterpretSuccess));
+    }
+
+    #[test]
+    fn resumes_through_the_checked_loop() {
+        let mut vm = machine(VmConfig::default(), false);
+        vm.load(countdown(Value::Int(3), Value::Int(1)));
+        vm.interrupt_handle().interrupt();
+        assert_eq!(vm.run(), InterpretResult::InterpretInterrupted);
+        assert_eq!(vm.ip(), 2);
+        assert!(!vm.can_run_verified());
+        assert_eq!(vm.run(), InterpretResult::InterpretSuccess);
+        assert_eq!(vm.stack(), &[Value::Int(0)]);
+
+        // a failed OpAdd takes both operands with it, which the verifier
+        // could not have foreseen, so running on has to check every pop
+        let chunk = compile_with("1 + (2.5 & 3)", CompileOptions::unoptimized()).unwrap();
+        let mut vm = machine(VmConfig::default(), false);
+        assert_eq!(vm.interpret(chunk), InterpretResult::InterpretRuntimeError);
+        assert_eq!(vm.run(), InterpretResult::InterpretRuntimeError);
+        assert_eq!(vm.runtime_error().unwrap().to_string(), "stack underflow in OpAdd\n[line 1] in script");
+
+        // an unverified chunk never takes the fast path
+        let mut c = Chunk::init_chunk();
+        c.write_to_chunk(OpCode::OpAdd.byte(), 1);
+        vm.load(c);
+        assert!(!vm.can_run_verified());
+        assert_eq!(vm.run(), InterpretResult::InterpretRuntimeError);
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::io;
+
+use crate::{OpCode, Value};
+
+// what went wrong while executing an instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind<V = Value> {
+    NoChunk,
+    RanOffEnd,
+    UnknownOpcode(u8),
+    TruncatedOperand(OpCode),
+    BadConstant(u8),
+    BadJump(OpCode),
+    StackUnderflow(OpCode),
+    DivisionByZero(OpCode),
+    NotAnInteger(OpCode),
+    NegativeShift(OpCode),
+    Interrupted,
+    StackOverflow,
+    OutOfMemory,
+    OutputFailed(io::ErrorKind),
+    Overflow { op: OpCode, left: Option<V>, right: V },
+}
+
+impl<V: fmt::Display> fmt::Display for ErrorKind<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::NoChunk => write!(f, "no chunk loaded"),
+            ErrorKind::RanOffEnd => write!(f, "ran past the end of the chunk"),
+            ErrorKind::UnknownOpcode(byte) => write!(f, "unknown opcode 0x{byte:02X}"),
+            ErrorKind::TruncatedOperand(op) => write!(f, "{op} is missing its operand"),
+            ErrorKind::BadConstant(index) => write!(f, "constant index {index} is out of range"),
+            ErrorKind::BadJump(op) => write!(f, "{op} jumps before the start of the chunk"),
+            ErrorKind::StackUnderflow(op) => write!(f, "stack underflow in {op}"),
+            ErrorKind::DivisionByZero(op) => write!(f, "division by zero in {op}"),
+            ErrorKind::NotAnInteger(op) => write!(f, "non-integer operand in {op}"),
+            ErrorKind::NegativeShift(op) => write!(f, "negative shift amount in {op}"),
+            ErrorKind::Interrupted => write!(f, "interrupted"),
+            ErrorKind::StackOverflow => write!(f, "stack overflow"),
+            ErrorKind::OutOfMemory => write!(f, "out of memory"),
+            ErrorKind::OutputFailed(kind) => write!(f, "could not write output: {kind}"),
+            ErrorKind::Overflow { op, left, right } => {
+                let symbol = match op {
+                    OpCode::OpAdd => "+",
+                    OpCode::OpSubtract | OpCode::OpNegate => "-",
+                    OpCode::OpMultiply => "*",
+                    OpCode::OpDivide => "/",
+                    OpCode::OpModulo => "%",
+                    OpCode::OpPower => "**",
+                    OpCode::OpShl => "<<",
+                    _ => "?",
+                };
+                match left {
+                    Some(left) => write!(f, "overflow in {op}: {left} {symbol} {right}"),
+                    None => write!(f, "overflow in {op}: {symbol}{right}"),
+                }
+            }
+        }
+    }
+}
+
+// a runtime error and where it happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError<V = Value> {
+    pub kind: ErrorKind<V>,
+    pub offset: usize,     //offset of the failing instruction
+    pub line: Option<u8>,  //its source line, if the chunk has one
+}
+
+impl<V: fmt::Display> fmt::Display for RuntimeError<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}\n[line {line}] in script", self.kind),
+            None => write!(f, "{}\n[offset {:04}] in script", self.kind, self.offset),
+        }
+    }
+}
+
+impl<V: fmt::Debug + fmt::Display> std::error::Error for RuntimeError<V> {}
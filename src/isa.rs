@@ -0,0 +1,179 @@
+use std::fmt;
+use std::str::FromStr;
+
+// operand bytes that follow an opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operands {
+    None,
+    // one byte index into the chunk's constants
+    Constant,
+    // big-endian u16 added to the ip after the operand
+    Jump,
+    // big-endian u16 subtracted from the ip after the operand
+    Loop,
+}
+
+impl Operands {
+    pub const fn width(self) -> usize {
+        match self {
+            Operands::None => 0,
+            Operands::Constant => 1,
+            Operands::Jump | Operands::Loop => 2,
+        }
+    }
+}
+
+// values an instruction pops and then pushes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+    pub pops: usize,
+    pub pushes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionInfo {
+    pub op: OpCode,
+    pub mnemonic: &'static str,
+    pub byte: u8,
+    pub operands: Operands,
+    pub stack: StackEffect,
+}
+
+// the instruction set; everything else about an opcode is derived from here
+macro_rules! instruction_set {
+    ($($name:ident = $byte:literal, $operands:ident, pops $pops:literal, pushes $pushes:literal;)*) => {
+        //operation codes
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[repr(u8)]
+        pub enum OpCode {
+            $($name = $byte,)*
+        }
+
+        // every instruction, in byte order
+        pub const INSTRUCTIONS: &[OpCode] = &[$(OpCode::$name,)*];
+
+        impl OpCode {
+            pub const fn info(self) -> &'static InstructionInfo {
+                match self {
+                    $(OpCode::$name => &InstructionInfo {
+                        op: OpCode::$name,
+                        mnemonic: stringify!($name),
+                        byte: $byte,
+                        operands: Operands::$operands,
+                        stack: StackEffect { pops: $pops, pushes: $pushes },
+                    },)*
+                }
+            }
+
+            pub const fn from_byte(byte: u8) -> Option<OpCode> {
+                match byte {
+                    $($byte => Some(OpCode::$name),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+instruction_set! {
+    OpReturn      = 0x00, None,     pops 0, pushes 0;
+    OpConstant    = 0x01, Constant, pops 0, pushes 1;
+    OpNegate      = 0x02, None,     pops 1, pushes 1;
+    OpAdd         = 0x03, None,     pops 2, pushes 1;
+    OpSubtract    = 0x04, None,     pops 2, pushes 1;
+    OpMultiply    = 0x05, None,     pops 2, pushes 1;
+    OpDivide      = 0x06, None,     pops 2, pushes 1;
+    OpModulo      = 0x07, None,     pops 2, pushes 1;
+    OpJump        = 0x08, Jump,     pops 0, pushes 0;
+    OpJumpIfFalse = 0x09, Jump,     pops 1, pushes 0;
+    OpLoop        = 0x0A, Loop,     pops 0, pushes 0;
+    OpPrint       = 0x0B, None,     pops 1, pushes 0;
+    OpPower       = 0x0C, None,     pops 2, pushes 1;
+    OpBitAnd      = 0x0D, None,     pops 2, pushes 1;
+    OpBitOr       = 0x0E, None,     pops 2, pushes 1;
+    OpBitXor      = 0x0F, None,     pops 2, pushes 1;
+    OpBitNot      = 0x10, None,     pops 1, pushes 1;
+    OpShl         = 0x11, None,     pops 2, pushes 1;
+    OpShr         = 0x12, None,     pops 2, pushes 1;
+    // stack shuffles, named after their Forth words
+    OpPop         = 0x13, None,     pops 1, pushes 0; // a ->
+    OpDup         = 0x14, None,     pops 1, pushes 2; // a -> a a
+    OpSwap        = 0x15, None,     pops 2, pushes 2; // a b -> b a
+    OpOver        = 0x16, None,     pops 2, pushes 3; // a b -> a b a
+    OpRot         = 0x17, None,     pops 3, pushes 3; // a b c -> b c a
+    // superinstructions: OpConstant then the operation, in one dispatch
+    OpAddConstant      = 0x18, Constant, pops 1, pushes 1;
+    OpSubtractConstant = 0x19, Constant, pops 1, pushes 1;
+}
+
+impl OpCode {
+    pub const fn byte(self) -> u8 {
+        self as u8
+    }
+
+    pub const fn mnemonic(self) -> &'static str {
+        self.info().mnemonic
+    }
+
+    pub const fn operands(self) -> Operands {
+        self.info().operands
+    }
+
+    pub const fn stack_effect(self) -> StackEffect {
+        self.info().stack
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownOpcode(pub u8);
+
+impl fmt::Display for UnknownOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown opcode 0x{:02X}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownOpcode {}
+
+impl TryFrom<u8> for OpCode {
+    type Error = UnknownOpcode;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        OpCode::from_byte(byte).ok_or(UnknownOpcode(byte))
+    }
+}
+
+impl From<OpCode> for u8 {
+    fn from(op: OpCode) -> u8 {
+        op.byte()
+    }
+}
+
+impl fmt::Display for OpCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.mnemonic())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownMnemonic(pub String);
+
+impl fmt::Display for UnknownMnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown mnemonic {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownMnemonic {}
+
+impl FromStr for OpCode {
+    type Err = UnknownMnemonic;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        INSTRUCTIONS
+            .iter()
+            .copied()
+            .find(|op| op.mnemonic() == s)
+            .ok_or_else(|| UnknownMnemonic(s.to_string()))
+    }
+}
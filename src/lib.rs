@@ -1,60 +1,85 @@
-pub mod scanner;
-pub use scanner::{Scanner, Token, TokenType};
-pub type Value = u8;
-
-//operation codes
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum OpCode {
-    OpReturn, 
-    OpConstant, 
-    OpNegate, 
-    OpAdd, 
-    OpSubtract, 
-    OpMultiply, 
-    OpDivide,
-    OpModulo,
-
-}
+pub mod bigint;
+pub mod builder;
+pub mod compiler;
+pub mod config;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+mod dispatch;
+pub mod error;
+pub mod isa;
+mod json;
+pub mod number;
+pub mod optimizer;
+mod output;
+pub mod profiler;
+pub mod register;
+pub mod scanners;
+#[cfg(test)]
+mod testing;
+pub mod value;
+pub mod verifier;
+pub use bigint::BigInt;
+pub use builder::{BuildError, ChunkBuilder, JumpLabel, LoopLabel};
+pub use compiler::{CompileError, CompileOptions};
+pub use config::{Arithmetic, VmBuilder, VmConfig};
+pub use coverage::Coverage;
+pub use debugger::{Breakpoint, Debugger, StopReason};
+pub use error::{ErrorKind, RuntimeError};
+pub use profiler::Profiler;
+pub use register::{RegisterChunk, RegisterMachine, TranslateError};
+pub use number::{Number, NumberError};
+pub use isa::{INSTRUCTIONS, InstructionInfo, OpCode, Operands, StackEffect, UnknownMnemonic, UnknownOpcode};
+pub use scanners::{Scanner, Token, TokenType};
+pub use verifier::VerifyError;
+pub use value::Value;
+
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 //helper function to convert OpCode to u8
 pub fn opcode_to_u8(op: OpCode) -> u8 {
-    match op {
-        OpCode::OpReturn   => 0x00,
-        OpCode::OpConstant => 0x01,
-        OpCode::OpNegate   => 0x02,
-        OpCode::OpAdd      => 0x03,
-        OpCode::OpSubtract => 0x04,
-        OpCode::OpMultiply => 0x05,
-        OpCode::OpDivide   => 0x06,
-        OpCode::OpModulo   => 0x07,
-    }
+    op.byte()
 }
 
 //helper function to convert u8 to OpCode
 pub fn u8_to_opcode(b: u8) -> Option<OpCode> {
-    Some(match b {
-        0x00 => OpCode::OpReturn,
-        0x01 => OpCode::OpConstant,
-        0x02 => OpCode::OpNegate,
-        0x03 => OpCode::OpAdd,
-        0x04 => OpCode::OpSubtract,
-        0x05 => OpCode::OpMultiply,
-        0x06 => OpCode::OpDivide,
-        0x07 => OpCode::OpModulo,
-        _ => return None,
-    })
-}
-
-#[derive(Debug)]
-pub struct Chunk {
+    OpCode::from_byte(b)
+}
+
+//number of operand bytes that follow the opcode
+pub fn operand_width(op: OpCode) -> usize {
+    op.operands().width()
+}
+
+#[derive(Debug, Clone)]
+pub struct Chunk<V = Value> {
     //constants: Vec<Value>, //constants used in the bytecode
     code: Vec<u8>,       //bytecode
     lines: Vec<u8>,  //line numbers for each bytecode instruction
-    values: Vec<Value>, //constants used in the bytecode
+    values: Vec<V>, //constants used in the bytecode
 }
 
 impl Chunk {
     pub fn init_chunk() -> Self {
+        Chunk::new()
+    }
+
+    pub fn builder() -> ChunkBuilder {
+        ChunkBuilder::default()
+    }
+}
+
+impl<V: Number> Default for Chunk<V> {
+    fn default() -> Self {
+        Chunk::new()
+    }
+}
+
+impl<V: Number> Chunk<V> {
+    //an empty chunk for any value type, e.g. Chunk::<i64>::new()
+    pub fn new() -> Self {
         Chunk {
             //constants: Vec::new(),
             code: Vec::new(),
@@ -68,7 +93,7 @@ impl Chunk {
         self.lines.push(line);
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 {
+    pub fn add_constant(&mut self, value: V) -> u8 {
         self.values.push(value);
         (self.values.len() - 1) as u8 //return the index of the added constant
         
@@ -76,6 +101,31 @@ impl Chunk {
 
 
 
+    //source line of the instruction at offset
+    pub fn line_at(&self, offset: usize) -> Option<u8> {
+        self.lines.get(offset).copied()
+    }
+
+    //offset of the first instruction compiled from a source line
+    pub fn first_offset_of_line(&self, line: u8) -> Option<usize> {
+        let mut offset = 0;
+        while offset < self.code.len() {
+            if self.lines[offset] == line {
+                return Some(offset);
+            }
+            offset = u8_to_opcode(self.code[offset]).map_or(offset + 1, |op| offset + 1 + op.operands().width());
+        }
+        None
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
     pub fn disassemble(&self, name: &str) {
         println!("== {} ==", name);
         let mut offset = 0usize;
@@ -84,97 +134,415 @@ impl Chunk {
         }
     }
     pub fn disassemble_instruction(&self, offset: usize) -> usize {
+        let (text, next) = self.describe_instruction(offset);
+        println!("{text}");
+        next
+    }
+
+    //one disassembled line for the instruction at offset, and the offset of the next one
+    pub fn describe_instruction(&self, offset: usize) -> (String, usize) {
         use std::fmt::Write as _;
         let line = self.lines.get(offset).copied().unwrap_or(0);
         let mut out = String::new();
 
         let byte = self.code[offset];
         let next = if let Some(op) = u8_to_opcode(byte) {
-            match op {
-                OpCode::OpReturn => {
-                    let _ = write!(out, "{offset:04}  line {:>4}  {:<12}", line, "OpReturn");
-                    offset + 1
-                }
-                OpCode::OpConstant => {
+            let _ = write!(out, "{offset:04}  line {:>4}  {:<12}", line, op);
+            match op.operands() {
+                Operands::None => {}
+                Operands::Constant => {
                     // format: [OpConstant][const_index]
                     let idx = self.code.get(offset + 1).copied().unwrap_or(0);
-                    let value = self.values.get(idx as usize).copied();
-                    let _ = write!(
-                        out,
-                        "{offset:04}  line {:>4}  {:<12} idx={:<3} value={:?}",
-                        line, "OpConstant", idx, value
-                    );
-                    offset + 2
-                }
-                OpCode::OpNegate => {
-                    let _ = write!(out, "{offset:04}  line {:>4}  {:<12}", line, "OpNegate");
-                    offset + 1
+                    let value = self.values.get(idx as usize);
+                    let _ = write!(out, " idx={:<3} value={:?}", idx, value);
                 }
-                OpCode::OpAdd => {
-                    let _ = write!(out, "{offset:04}  line {:>4}  {:<12}", line, "OpAdd");
-                    offset + 1
+                Operands::Jump | Operands::Loop => {
+                    // format: [op][hi][lo], relative to the next instruction
+                    let hi = self.code.get(offset + 1).copied().unwrap_or(0) as isize;
+                    let lo = self.code.get(offset + 2).copied().unwrap_or(0) as isize;
+                    let jump = (hi << 8) | lo;
+                    let next = (offset + 3) as isize;
+                    let target = if op.operands() == Operands::Loop { next - jump } else { next + jump };
+                    let _ = write!(out, " {offset:04} -> {target:04}");
                 }
-                OpCode::OpSubtract => {
-                    let _ = write!(out, "{offset:04}  line {:>4}  {:<12}", line, "OpSubtract");
-                    offset + 1
-                }
-                OpCode::OpMultiply => {
-                    let _ = write!(out, "{offset:04}  line {:>4}  {:<12}", line, "OpMultiply");
-                    offset + 1
-                }
-                OpCode::OpDivide => {
-                    let _ = write!(out, "{offset:04}  line {:>4}  {:<12}", line, "OpDivide");
-                    offset + 1
-                }
-                OpCode::OpModulo => {
-                    let _ = write!(out, "{offset:04}  line {:>4}  {:<12}", line, "OpModulo");
-                offset + 1
-                }
-
             }
+            offset + 1 + op.operands().width()
         } else {
             let _ = write!(out, "{offset:04}  line {:>4}  {:<12} 0x{:02X} (unknown)", line, "????", byte);
             offset + 1
         };
 
-        println!("{out}");
-        next
+        (out, next)
     }
 }
 
-pub struct VirtualMachine {
-    pub chunk: Option<Chunk>,
-    pub ip: usize,
-    pub stack: Vec<Value>,
+pub struct VirtualMachine<V = Value> {
+    chunk: Option<Chunk<V>>,
+    verified: bool, //the loaded chunk passed Chunk::verify
+    ip: usize,
+    finished: bool, //OpReturn ran; nothing more runs until the next load
+    stack: Vec<V>,
+    heap: usize, //Number::heap_size of everything on the stack
+    trace: Option<Box<dyn Write>>, //execution trace sink, if tracing
+    profiler: Option<Profiler>,    //execution counts, if profiling
+    coverage: Option<Coverage>,    //executed offsets, if collecting coverage
+    fuel: Option<u64>,             //instruction budget left, None for unlimited
+    fuel_costs: Option<Box<[u64; 256]>>, //fuel per opcode byte, 1 when unset
+    error: Option<RuntimeError<V>>, //why the last run stopped with a runtime error
+    interrupt: Arc<AtomicBool>,    //set from other threads to stop at the next backward jump
+    config: VmConfig,              //resource limits and arithmetic policy
+    output: Option<Box<dyn Write>>, //where print goes, stdout when unset
+    input: Option<Box<dyn BufRead>>, //where lines are read from, stdin when unset
+}
+
+//stops a running VM from another thread; see VirtualMachine::interrupt_handle
+#[derive(Debug, Clone)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    //ask the VM to stop with InterpretInterrupted at its next safe point, a
+    //backward jump; a request no jump has seen is dropped by the next load().
+    //straight-line code has no safe point and always runs to its end. the
+    //resulting RuntimeError says where the VM stopped, the jump's offset and
+    //source line, and nothing more: without call frames there is no stack
+    //trace to report
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
 }
 
 impl VirtualMachine {
     pub fn init_machine() -> Self {
+        VirtualMachine::new()
+    }
+
+    pub fn builder() -> VmBuilder {
+        VmBuilder::default()
+    }
+}
+
+impl<V: Number> Default for VirtualMachine<V> {
+    fn default() -> Self {
+        VirtualMachine::new()
+    }
+}
+
+impl<V: Number> VirtualMachine<V> {
+    //a machine for any value type, e.g. VirtualMachine::<f64>::new()
+    pub fn new() -> Self {
         VirtualMachine {
             chunk: None,
+            verified: false,
             ip: 0,
+            finished: false,
             stack: Vec::new(),
+            heap: 0,
+            trace: None,
+            profiler: None,
+            coverage: None,
+            fuel: None,
+            fuel_costs: None,
+            error: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            config: VmConfig::default(),
+            output: None,
+            input: None,
+        }
+    }
+
+    //the loaded chunk, if any
+    pub fn chunk(&self) -> Option<&Chunk<V>> {
+        self.chunk.as_ref()
+    }
+
+    //offset of the next instruction to execute
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    //the value stack, bottom first
+    pub fn stack(&self) -> &[V] {
+        &self.stack
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    //change the limits; they apply from the next instruction on
+    pub fn set_config(&mut self, config: VmConfig) {
+        self.config = config;
+    }
+
+    //bytes the values on the stack own, such as the limbs of big integers;
+    //this is what VmConfig::max_heap_bytes limits
+    pub fn heap_bytes(&self) -> usize {
+        self.heap
+    }
+
+    //print the stack and each instruction to sink before executing it
+    pub fn set_trace(&mut self, sink: Box<dyn Write>) {
+        self.trace = Some(sink);
+    }
+
+    //stop tracing and hand back the sink
+    pub fn take_trace(&mut self) -> Option<Box<dyn Write>> {
+        self.trace.take()
+    }
+
+    //send printed values to sink instead of stdout
+    pub fn set_output(&mut self, sink: Box<dyn Write>) {
+        self.output = Some(sink);
+    }
+
+    //go back to stdout and hand back the sink
+    pub fn take_output(&mut self) -> Option<Box<dyn Write>> {
+        self.output.take()
+    }
+
+    //read lines from source instead of stdin
+    pub fn set_input(&mut self, source: Box<dyn BufRead>) {
+        self.input = Some(source);
+    }
+
+    //go back to stdin and hand back the source
+    pub fn take_input(&mut self) -> Option<Box<dyn BufRead>> {
+        self.input.take()
+    }
+
+    //reads one line from the input, without its line ending; None at end of input
+    pub fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        let read = match self.input.as_mut() {
+            Some(source) => source.read_line(&mut line)?,
+            None => io::stdin().lock().read_line(&mut line)?,
+        };
+        if read == 0 {
+            return Ok(None);
+        }
+        let trimmed = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(trimmed);
+        Ok(Some(line))
+    }
+
+    //count executed opcodes, offsets and lines in profiler
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    //stop profiling and hand back the counts
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    //mark every executed offset in coverage
+    pub fn set_coverage(&mut self, coverage: Coverage) {
+        self.coverage = Some(coverage);
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    //stop collecting coverage and hand back what was recorded
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    //limit execution to fuel units; None removes the limit
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    //top up the budget; a VM that ran out can then continue with run()
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(left) = self.fuel.as_mut() {
+            *left = left.saturating_add(fuel);
+        } else {
+            self.fuel = Some(fuel);
         }
     }
 
-    pub fn interpret(&mut self, chunk: Chunk) -> InterpretResult {
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    //fuel charged for each execution of op
+    pub fn set_opcode_cost(&mut self, op: OpCode, cost: u64) {
+        let costs = self.fuel_costs.get_or_insert_with(|| Box::new([1; 256]));
+        costs[op.byte() as usize] = cost;
+    }
+
+    //a handle other threads can use to stop this VM
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle { flag: Arc::clone(&self.interrupt) }
+    }
+
+    //details of the most recent InterpretRuntimeError or InterpretInterrupted
+    pub fn runtime_error(&self) -> Option<&RuntimeError<V>> {
+        self.error.as_ref()
+    }
+
+    pub fn interpret(&mut self, chunk: Chunk<V>) -> InterpretResult {
         // load the chunk into the VM
-        self.chunk = Some(chunk);
-        // reset instruction pointer
-        self.ip = 0;
+        self.load(chunk);
         // call run
         self.run()
     }
+
+    //load a chunk and reset the instruction pointer without running it. a
+    //pending interrupt was meant for the previous chunk, so it is cleared
+    pub fn load(&mut self, chunk: Chunk<V>) {
+        self.verified = chunk.verify().is_ok();
+        self.chunk = Some(chunk);
+        self.ip = 0;
+        self.finished = false;
+        self.error = None;
+        self.interrupt.store(false, Ordering::Relaxed);
+    }
+
+    //executes a single instruction
+    pub fn step(&mut self) -> StepResult<V> {
+        self.run_for(1)
+    }
+
+    //executes at most n instructions, keeping ip and the stack for the next call
+    pub fn run_for(&mut self, n: usize) -> StepResult<V> {
+        if self.finished {
+            return StepResult::Finished(self.stack.last().cloned());
+        }
+        let instrumented = self.instrumented();
+        for _ in 0..n {
+            let stopped = if instrumented {
+                self.before_instruction().or_else(|| self.execute_instruction())
+            } else {
+                self.execute_instruction()
+            };
+            match stopped {
+                None => {}
+                Some(InterpretResult::InterpretSuccess) => {
+                    self.finished = true;
+                    return StepResult::Finished(self.stack.last().cloned());
+                }
+                Some(InterpretResult::InterpretOutOfFuel) => return StepResult::OutOfFuel,
+                Some(result) => {
+                    let error = self.error.clone().unwrap_or(RuntimeError {
+                        kind: ErrorKind::NoChunk,
+                        offset: self.ip,
+                        line: None,
+                    });
+                    return match result {
+                        InterpretResult::InterpretInterrupted => StepResult::Interrupted(error),
+                        _ => StepResult::Error(error),
+                    };
+                }
+            }
+        }
+        StepResult::Paused
+    }
     
+    fn instrumented(&self) -> bool {
+        self.trace.is_some() || self.profiler.is_some() || self.coverage.is_some() || self.fuel.is_some()
+    }
+
     pub fn run(&mut self) -> InterpretResult {
-    loop {
+        if self.finished {
+            return InterpretResult::InterpretSuccess;
+        }
+        // pick the loop once so a run without tracing, profiling, coverage
+        // or fuel carries no instrumentation code at all, and a verified one
+        // none of the checks the verifier made
+        let result = if self.instrumented() {
+            self.run_loop::<true>()
+        } else if self.can_run_verified() {
+            self.run_verified()
+        } else {
+            self.run_loop::<false>()
+        };
+        self.finished = result == InterpretResult::InterpretSuccess;
+        result
+    }
+
+    fn run_loop<const INSTRUMENTED: bool>(&mut self) -> InterpretResult {
+        loop {
+            if INSTRUMENTED && let Some(result) = self.before_instruction() {
+                return result;
+            }
+            if let Some(result) = self.execute_instruction() {
+                return result;
+            }
+        }
+    }
+
+    //runs instrumentation for the next instruction; Some(result) to stop before it
+    fn before_instruction(&mut self) -> Option<InterpretResult> {
+        let Some(chunk) = &self.chunk else {
+            return None;
+        };
+        if self.ip >= chunk.code.len() {
+            return None;
+        }
+        if let Some(left) = self.fuel.as_mut() {
+            let byte = chunk.code[self.ip] as usize;
+            let cost = self.fuel_costs.as_ref().map_or(1, |costs| costs[byte]);
+            // stop with ip still on this instruction so run() can resume it
+            if *left < cost {
+                return Some(InterpretResult::InterpretOutOfFuel);
+            }
+            *left -= cost;
+        }
+        if let Some(sink) = self.trace.as_mut() {
+            let _ = write!(sink, "          ");
+            for value in &self.stack {
+                let _ = write!(sink, "[ {value} ]");
+            }
+            let _ = writeln!(sink);
+            let _ = writeln!(sink, "{}", chunk.describe_instruction(self.ip).0);
+        }
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(chunk, self.ip);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(chunk, self.ip);
+        }
+        None
+    }
+
+    //executes one instruction; Some(result) once execution has stopped
+    #[inline(always)]
+    fn execute_instruction(&mut self) -> Option<InterpretResult> {
+        let offset = self.ip;
+        match self.dispatch() {
+            Ok(result) => result,
+            Err(kind) => Some(self.fail(kind, offset)),
+        }
+    }
+
+    //records why the instruction at offset stopped the run
+    fn fail(&mut self, kind: ErrorKind<V>, offset: usize) -> InterpretResult {
+        let line = self.chunk.as_ref().and_then(|c| c.line_at(offset));
+        let result = match kind {
+            ErrorKind::Interrupted => InterpretResult::InterpretInterrupted,
+            _ => InterpretResult::InterpretRuntimeError,
+        };
+        self.error = Some(RuntimeError { kind, offset, line });
+        result
+    }
+
+    #[inline(always)]
+    fn dispatch(&mut self) -> Result<Option<InterpretResult>, ErrorKind<V>> {
         let chunk = match &self.chunk {
             Some(c) => c,
-            None => return InterpretResult::InterpretRuntimeError,
+            None => return Err(ErrorKind::NoChunk),
         };
-
         if self.ip >= chunk.code.len() {
-            return InterpretResult::InterpretRuntimeError;
+            return Err(ErrorKind::RanOffEnd);
         }
 
         let instruction = chunk.code[self.ip];
@@ -183,79 +551,253 @@ impl VirtualMachine {
         match u8_to_opcode(instruction) {
             Some(OpCode::OpReturn) => {
                 // Stop execution
-                return InterpretResult::InterpretSuccess;
+                return Ok(Some(InterpretResult::InterpretSuccess));
             }
-            Some(OpCode::OpConstant) => {
+            Some(op @ OpCode::OpConstant) => {
                 if self.ip >= chunk.code.len() {
-                    return InterpretResult::InterpretRuntimeError;
+                    return Err(ErrorKind::TruncatedOperand(op));
                 }
-                let constant_index = chunk.code[self.ip] as usize;
+                let constant_index = chunk.code[self.ip];
                 self.ip += 1;
-                if let Some(value) = chunk.values.get(constant_index) {
-                    self.push(*value);
+                if let Some(value) = chunk.values.get(constant_index as usize) {
+                    self.push(value.clone())?;
                 } else {
-                    return InterpretResult::InterpretRuntimeError;
+                    return Err(ErrorKind::BadConstant(constant_index));
                 }
             }
-            Some(OpCode::OpNegate) => {
-                if let Some(v) = self.pop() {
-                    self.push(v.wrapping_neg()); // negate u8 safely
-                } else {
-                    return InterpretResult::InterpretRuntimeError;
+            Some(op @ OpCode::OpNegate) => {
+                let Some(v) = self.pop() else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let result = v.neg(self.config.arithmetic);
+                self.push(Self::arithmetic_result(op, None, v, result)?)?;
+            }
+            Some(op @ OpCode::OpAdd) => {
+                let (Some(b), Some(a)) = (self.pop(), self.pop()) else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let result = a.add(&b, self.config.arithmetic);
+                self.push(Self::arithmetic_result(op, Some(a), b, result)?)?;
+            }
+            Some(op @ OpCode::OpSubtract) => {
+                let (Some(b), Some(a)) = (self.pop(), self.pop()) else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let result = a.sub(&b, self.config.arithmetic);
+                self.push(Self::arithmetic_result(op, Some(a), b, result)?)?;
+            }
+            Some(op @ OpCode::OpMultiply) => {
+                let (Some(b), Some(a)) = (self.pop(), self.pop()) else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let result = a.mul(&b, self.config.arithmetic);
+                self.push(Self::arithmetic_result(op, Some(a), b, result)?)?;
+            }
+            Some(op @ OpCode::OpDivide) => {
+                let (Some(b), Some(a)) = (self.pop(), self.pop()) else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let result = a.div(&b, self.config.arithmetic);
+                self.push(Self::arithmetic_result(op, Some(a), b, result)?)?;
+            }
+            Some(op @ OpCode::OpModulo) => {
+                let (Some(b), Some(a)) = (self.pop(), self.pop()) else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let result = a.rem(&b, self.config.arithmetic);
+                self.push(Self::arithmetic_result(op, Some(a), b, result)?)?;
+            }
+            Some(op @ OpCode::OpPower) => {
+                let (Some(b), Some(a)) = (self.pop(), self.pop()) else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let result = a.pow(&b, self.config.arithmetic);
+                self.push(Self::arithmetic_result(op, Some(a), b, result)?)?;
+            }
+            Some(op @ OpCode::OpBitAnd) => {
+                let (Some(b), Some(a)) = (self.pop(), self.pop()) else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let result = a.bit_and(&b);
+                self.push(Self::arithmetic_result(op, Some(a), b, result)?)?;
+            }
+            Some(op @ OpCode::OpBitOr) => {
+                let (Some(b), Some(a)) = (self.pop(), self.pop()) else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let result = a.bit_or(&b);
+                self.push(Self::arithmetic_result(op, Some(a), b, result)?)?;
+            }
+            Some(op @ OpCode::OpBitXor) => {
+                let (Some(b), Some(a)) = (self.pop(), self.pop()) else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let result = a.bit_xor(&b);
+                self.push(Self::arithmetic_result(op, Some(a), b, result)?)?;
+            }
+            Some(op @ OpCode::OpShl) => {
+                let (Some(b), Some(a)) = (self.pop(), self.pop()) else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let result = a.shl(&b, self.config.arithmetic);
+                self.push(Self::arithmetic_result(op, Some(a), b, result)?)?;
+            }
+            Some(op @ OpCode::OpShr) => {
+                let (Some(b), Some(a)) = (self.pop(), self.pop()) else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let result = a.shr(&b);
+                self.push(Self::arithmetic_result(op, Some(a), b, result)?)?;
+            }
+            // errors name the operation the superinstruction stands in for
+            Some(op @ (OpCode::OpAddConstant | OpCode::OpSubtractConstant)) => {
+                if self.ip >= chunk.code.len() {
+                    return Err(ErrorKind::TruncatedOperand(op));
                 }
+                let constant_index = chunk.code[self.ip];
+                self.ip += 1;
+                let Some(b) = chunk.values.get(constant_index as usize).cloned() else {
+                    return Err(ErrorKind::BadConstant(constant_index));
+                };
+                let Some(a) = self.pop() else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let (op, result) = match op {
+                    OpCode::OpAddConstant => (OpCode::OpAdd, a.add(&b, self.config.arithmetic)),
+                    _ => (OpCode::OpSubtract, a.sub(&b, self.config.arithmetic)),
+                };
+                self.push(Self::arithmetic_result(op, Some(a), b, result)?)?;
             }
-            Some(OpCode::OpAdd) => {
-                if let (Some(b), Some(a)) = (self.pop(), self.pop()) {
-                    self.push(a.wrapping_add(b));
-                } else {
-                    return InterpretResult::InterpretRuntimeError;
+            Some(op @ OpCode::OpPop) => {
+                if self.pop().is_none() {
+                    return Err(ErrorKind::StackUnderflow(op));
                 }
             }
-            Some(OpCode::OpSubtract) => {
-                if let (Some(b), Some(a)) = (self.pop(), self.pop()) {
-                    self.push(a.wrapping_sub(b));
-                } else {
-                    return InterpretResult::InterpretRuntimeError;
+            Some(op @ OpCode::OpDup) => {
+                let Some(top) = self.stack.last().cloned() else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                self.push(top)?;
+            }
+            Some(op @ OpCode::OpSwap) => {
+                let len = self.stack.len();
+                if len < 2 {
+                    return Err(ErrorKind::StackUnderflow(op));
                 }
+                self.stack.swap(len - 2, len - 1);
             }
-            Some(OpCode::OpMultiply) => {
-                if let (Some(b), Some(a)) = (self.pop(), self.pop()) {
-                    self.push(a.wrapping_mul(b));
-                } else {
-                    return InterpretResult::InterpretRuntimeError;
+            Some(op @ OpCode::OpOver) => {
+                let len = self.stack.len();
+                if len < 2 {
+                    return Err(ErrorKind::StackUnderflow(op));
                 }
+                let second = self.stack[len - 2].clone();
+                self.push(second)?;
             }
-            Some(OpCode::OpDivide) => {
-                if let (Some(b), Some(a)) = (self.pop(), self.pop()) {
-                    if b == 0 {
-                        return InterpretResult::InterpretRuntimeError;
-                    }
-                    self.push(a / b);
-                } else {
-                    return InterpretResult::InterpretRuntimeError;
+            Some(op @ OpCode::OpRot) => {
+                let len = self.stack.len();
+                if len < 3 {
+                    return Err(ErrorKind::StackUnderflow(op));
                 }
+                self.stack[len - 3..].rotate_left(1);
             }
-            Some(OpCode::OpModulo) => {
-                if let (Some(b), Some(a)) = (self.pop(), self.pop()) {
-                    if b == 0 {
-                        return InterpretResult::InterpretRuntimeError;
-                    }
-                    self.push(a % b);
-                } else {
-                    return InterpretResult::InterpretRuntimeError;
+            Some(op @ OpCode::OpBitNot) => {
+                let Some(v) = self.pop() else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let result = v.bit_not();
+                self.push(Self::arithmetic_result(op, None, v, result)?)?;
+            }
+            Some(op @ OpCode::OpJump) => {
+                let Some(jump) = Self::read_short(chunk, self.ip) else {
+                    return Err(ErrorKind::TruncatedOperand(op));
+                };
+                self.ip += 2 + jump;
+            }
+            Some(op @ OpCode::OpJumpIfFalse) => {
+                let Some(jump) = Self::read_short(chunk, self.ip) else {
+                    return Err(ErrorKind::TruncatedOperand(op));
+                };
+                self.ip += 2;
+                match self.pop() {
+                    Some(v) if v.is_zero() => self.ip += jump, // zero is the only falsey value
+                    Some(_) => {}
+                    None => return Err(ErrorKind::StackUnderflow(op)),
                 }
             }
-            None => return InterpretResult::InterpretRuntimeError,
+            Some(op @ OpCode::OpLoop) => {
+                let Some(jump) = Self::read_short(chunk, self.ip) else {
+                    return Err(ErrorKind::TruncatedOperand(op));
+                };
+                match (self.ip + 2).checked_sub(jump) {
+                    Some(target) => self.ip = target,
+                    None => return Err(ErrorKind::BadJump(op)),
+                }
+                // every loop passes through here, so this is the only check needed;
+                // the jump has been taken, so running again resumes the loop
+                if self.interrupt.load(Ordering::Relaxed) && self.interrupt.swap(false, Ordering::Relaxed) {
+                    return Err(ErrorKind::Interrupted);
+                }
+            }
+            Some(op @ OpCode::OpPrint) => {
+                let Some(value) = self.pop() else {
+                    return Err(ErrorKind::StackUnderflow(op));
+                };
+                let written = match self.output.as_mut() {
+                    Some(sink) => writeln!(sink, "{value}"),
+                    None => writeln!(io::stdout(), "{value}"),
+                };
+                if let Err(e) = written {
+                    return Err(ErrorKind::OutputFailed(e.kind()));
+                }
+            }
+            None => return Err(ErrorKind::UnknownOpcode(instruction)),
+        }
+        Ok(None)
+    }
+
+    //turns a failed operation into the runtime error for op; left is None for unary operations
+    #[inline(always)]
+    fn arithmetic_result(op: OpCode, left: Option<V>, right: V, result: Result<V, NumberError>) -> Result<V, ErrorKind<V>> {
+        result.map_err(|e| Self::arithmetic_error(op, left, right, e))
+    }
+
+    #[cold]
+    pub(crate) fn arithmetic_error(op: OpCode, left: Option<V>, right: V, error: NumberError) -> ErrorKind<V> {
+        match error {
+            NumberError::Overflow => ErrorKind::Overflow { op, left, right },
+            NumberError::DivisionByZero => ErrorKind::DivisionByZero(op),
+            NumberError::NotAnInteger => ErrorKind::NotAnInteger(op),
+            NumberError::NegativeShift => ErrorKind::NegativeShift(op),
         }
     }
-} 
 
-    fn push(&mut self, value: Value) {
+    //reads the big-endian u16 operand of a jump instruction
+    fn read_short(chunk: &Chunk<V>, at: usize) -> Option<usize> {
+        let hi = *chunk.code.get(at)? as usize;
+        let lo = *chunk.code.get(at + 1)? as usize;
+        Some((hi << 8) | lo)
+    }
+
+
+    #[inline(always)]
+    fn push(&mut self, value: V) -> Result<(), ErrorKind<V>> {
+        if self.stack.len() >= self.config.max_stack {
+            return Err(ErrorKind::StackOverflow);
+        }
+        let size = value.heap_size();
+        if self.heap + size > self.config.max_heap_bytes {
+            return Err(ErrorKind::OutOfMemory);
+        }
+        self.heap += size;
         self.stack.push(value);
+        Ok(())
     }
 
-    fn pop(&mut self) -> Option<Value> {
-        self.stack.pop()
+    fn pop(&mut self) -> Option<V> {
+        let value = self.stack.pop()?;
+        self.heap -= value.heap_size();
+        Some(value)
     }
 
     pub fn interpret_source(&mut self, source_code: &str) -> InterpretResult { 
@@ -283,20 +825,31 @@ impl VirtualMachine {
         let len = token.value.len();                             
         println!("{:?} {}, {:?}", token.token_type, len, text);  
 
-        match token.token_type {                      
-            TokenType::TokenEof => break,             
-            _ => {}                                   
-        }                                             
+        if token.token_type == TokenType::TokenEof {
+            break;
+        }
     }                                                 
     } 
         
 }
 
-#[derive(Debug, PartialEq)]
+//outcome of a bounded run with step() or run_for()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepResult<V = Value> {
+    Paused,               //budget used up, call again to continue
+    Finished(Option<V>),  //OpReturn ran; the value on top of the stack, again on every call until the next load
+    OutOfFuel,
+    Interrupted(RuntimeError<V>), //interrupt() was called; where it stopped
+    Error(RuntimeError<V>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InterpretResult {
     InterpretSuccess,
     InterpretCompileError,
     InterpretRuntimeError,
+    InterpretOutOfFuel,
+    InterpretInterrupted,
 }
 
 
@@ -305,27 +858,27 @@ pub enum InterpretResult {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::output::SharedBuf;
 
     #[test]
     fn opcode_mappings_roundtrip() {
-        let table = [
-            (OpCode::OpReturn,   0x00),
-            (OpCode::OpConstant, 0x01),
-            (OpCode::OpNegate,   0x02),
-            (OpCode::OpAdd,      0x03),
-            (OpCode::OpSubtract, 0x04),
-            (OpCode::OpMultiply, 0x05),
-            (OpCode::OpDivide,   0x06),
-            (OpCode::OpModulo,   0x07),
-        ];
-
-        for (op, byte) in table {
+        for &op in INSTRUCTIONS {
+            let byte = op.info().byte;
             assert_eq!(opcode_to_u8(op), byte, "opcode_to_u8 mismatch for {op:?}");
             assert_eq!(u8_to_opcode(byte), Some(op), "u8_to_opcode mismatch for 0x{byte:02X}");
+            assert_eq!(OpCode::try_from(byte), Ok(op));
+            assert_eq!(op.to_string().parse::<OpCode>(), Ok(op));
+            assert_eq!(op.to_string(), format!("{op:?}"));
         }
 
-        // Unknown opcode should map to None
-        assert_eq!(u8_to_opcode(0xFF), None);
+        // every other byte is unknown
+        for byte in 0..=u8::MAX {
+            if !INSTRUCTIONS.iter().any(|op| op.byte() == byte) {
+                assert_eq!(u8_to_opcode(byte), None);
+                assert_eq!(OpCode::try_from(byte), Err(UnknownOpcode(byte)));
+            }
+        }
+        assert!("OpNope".parse::<OpCode>().is_err());
     }
 
     #[test]
@@ -333,12 +886,12 @@ mod tests {
         let mut c = Chunk::init_chunk();
 
         // Add a couple constants; verify indices and stored values.
-        let i0 = c.add_constant(15);
-        let i1 = c.add_constant(42);
+        let i0 = c.add_constant(Value::Int(15));
+        let i1 = c.add_constant(Value::Int(42));
         assert_eq!(i0, 0);
         assert_eq!(i1, 1);
-        assert_eq!(c.values[i0 as usize], 15);
-        assert_eq!(c.values[i1 as usize], 42);
+        assert_eq!(c.values[i0 as usize], Value::Int(15));
+        assert_eq!(c.values[i1 as usize], Value::Int(42));
 
         // Write opcode + operand pairs, then a Return.
         c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), 10);
@@ -368,11 +921,11 @@ mod tests {
         let mut c = Chunk::init_chunk();
 
         // Build: OpConstant idx0 | OpConstant idx1 | OpAdd | 0xFF(unknown) | OpReturn
-        let i0 = c.add_constant(10);
+        let i0 = c.add_constant(Value::Int(10));
         c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), 1);
         c.write_to_chunk(i0, 1);
 
-        let i1 = c.add_constant(20);
+        let i1 = c.add_constant(Value::Int(20));
         c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), 2);
         c.write_to_chunk(i1, 2);
 
@@ -410,30 +963,30 @@ fn vm_exec_simple_arith() {
     let mut c = Chunk::init_chunk();
     let l = 1;
 
-    let i8 = c.add_constant(8);
+    let i8 = c.add_constant(Value::Int(8));
     c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), l); c.write_to_chunk(i8, l);
 
-    let i2 = c.add_constant(2);
+    let i2 = c.add_constant(Value::Int(2));
     c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), l); c.write_to_chunk(i2, l);
 
     c.write_to_chunk(opcode_to_u8(OpCode::OpAdd), l);
 
-    let i3 = c.add_constant(3);
+    let i3 = c.add_constant(Value::Int(3));
     c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), l); c.write_to_chunk(i3, l);
 
     c.write_to_chunk(opcode_to_u8(OpCode::OpSubtract), l);
 
-    let i4 = c.add_constant(4);
+    let i4 = c.add_constant(Value::Int(4));
     c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), l); c.write_to_chunk(i4, l);
 
     c.write_to_chunk(opcode_to_u8(OpCode::OpMultiply), l);
 
-    let i5 = c.add_constant(5);
+    let i5 = c.add_constant(Value::Int(5));
     c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), l); c.write_to_chunk(i5, l);
 
     c.write_to_chunk(opcode_to_u8(OpCode::OpDivide), l);
 
-    let imod = c.add_constant(3);
+    let imod = c.add_constant(Value::Int(3));
     c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), l); c.write_to_chunk(imod, l);
 
     c.write_to_chunk(opcode_to_u8(OpCode::OpModulo), l);
@@ -443,15 +996,15 @@ fn vm_exec_simple_arith() {
     let mut vm = VirtualMachine::init_machine();
     let res = vm.interpret(c);
     assert_eq!(res, InterpretResult::InterpretSuccess);
-    assert_eq!(vm.stack.last().copied(), Some(-2));
+    assert_eq!(vm.stack.last(), Some(&Value::Int(-2)));
 }
 
 #[test]
 fn vm_divide_by_zero_runtime_error() {
     let mut c = Chunk::init_chunk();
     let l = 1;
-    let a = c.add_constant(10);
-    let b = c.add_constant(0);
+    let a = c.add_constant(Value::Int(10));
+    let b = c.add_constant(Value::Int(0));
 
     c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), l); c.write_to_chunk(a, l);
     c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), l); c.write_to_chunk(b, l);
@@ -468,7 +1021,7 @@ fn vm_stack_underflow_runtime_error() {
     // Attempt to add with only one value on the stack.
     let mut c = Chunk::init_chunk();
     let l = 1;
-    let a = c.add_constant(5);
+    let a = c.add_constant(Value::Int(5));
 
     c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), l); c.write_to_chunk(a, l);
     c.write_to_chunk(opcode_to_u8(OpCode::OpAdd), l);  // needs two values
@@ -478,4 +1031,412 @@ fn vm_stack_underflow_runtime_error() {
     let res = vm.interpret(c);
     assert_eq!(res, InterpretResult::InterpretRuntimeError);
 }
+
+#[test]
+fn vm_trace_prints_stack_before_each_instruction() {
+    let mut b = Chunk::builder();
+    b.emit_constant(Value::Int(15)).emit_constant(Value::Int(42)).emit_op(OpCode::OpAdd).emit_op(OpCode::OpReturn);
+    let chunk = b.finish().unwrap();
+
+    let buf = SharedBuf::default();
+    let mut vm = VirtualMachine::init_machine();
+    vm.set_trace(Box::new(buf.clone()));
+    assert_eq!(vm.interpret(chunk), InterpretResult::InterpretSuccess);
+
+    let text = String::from_utf8(buf.take()).unwrap();
+    let lines: Vec<&str> = text.lines().map(str::trim_end).collect();
+    assert_eq!(lines[0], "");
+    assert!(lines[1].starts_with("0000  line    1  OpConstant"));
+    assert_eq!(lines[2].trim(), "[ 15 ]");
+    assert_eq!(lines[4].trim(), "[ 15 ][ 42 ]");
+    assert!(lines[5].ends_with("OpAdd"));
+    assert_eq!(lines[6].trim(), "[ 57 ]");
+    assert!(lines[7].ends_with("OpReturn"));
+    assert_eq!(lines.len(), 8);
+}
+
+#[test]
+fn vm_out_of_fuel_is_resumable() {
+    // 1 + 2 + 3 + 4: 4 constants, 3 adds and a return
+    let mut b = Chunk::builder();
+    b.emit_constant(Value::Int(1));
+    for v in 2..=4 {
+        b.emit_constant(Value::Int(v)).emit_op(OpCode::OpAdd);
+    }
+    b.emit_op(OpCode::OpReturn);
+    let chunk = b.finish().unwrap();
+
+    let mut vm = VirtualMachine::init_machine();
+    vm.set_fuel(Some(3));
+    assert_eq!(vm.interpret(chunk), InterpretResult::InterpretOutOfFuel);
+    assert_eq!(vm.ip, 5); // stopped before the second OpConstant
+    assert_eq!(vm.stack, vec![Value::Int(3)]);
+    assert_eq!(vm.fuel(), Some(0));
+
+    vm.add_fuel(2);
+    assert_eq!(vm.run(), InterpretResult::InterpretOutOfFuel);
+    assert_eq!(vm.stack, vec![Value::Int(6)]);
+
+    vm.add_fuel(100);
+    assert_eq!(vm.run(), InterpretResult::InterpretSuccess);
+    assert_eq!(vm.stack, vec![Value::Int(10)]);
+    assert_eq!(vm.fuel(), Some(97));
+}
+
+#[test]
+fn vm_opcode_costs() {
+    let mut b = Chunk::builder();
+    b.emit_constant(Value::Int(6)).emit_constant(Value::Int(7)).emit_op(OpCode::OpMultiply).emit_op(OpCode::OpReturn);
+    let chunk = b.finish().unwrap();
+
+    let mut vm = VirtualMachine::init_machine();
+    vm.set_opcode_cost(OpCode::OpMultiply, 10);
+    vm.set_opcode_cost(OpCode::OpReturn, 0);
+    vm.set_fuel(Some(11));
+    // 2 constants leave 9, not enough for the multiply
+    assert_eq!(vm.interpret(chunk), InterpretResult::InterpretOutOfFuel);
+    assert_eq!(vm.fuel(), Some(9));
+    vm.add_fuel(1);
+    assert_eq!(vm.run(), InterpretResult::InterpretSuccess);
+    assert_eq!(vm.stack, vec![Value::Int(42)]);
+    assert_eq!(vm.fuel(), Some(0));
+}
+
+#[test]
+fn vm_run_for_interleaves_machines() {
+    let program = |x: i64| {
+        let mut b = Chunk::builder();
+        b.emit_constant(Value::Int(x)).emit_constant(Value::Int(2)).emit_op(OpCode::OpMultiply).emit_op(OpCode::OpReturn);
+        b.finish().unwrap()
+    };
+    let mut vms: Vec<VirtualMachine> = (1..=3).map(|_| VirtualMachine::init_machine()).collect();
+    for (i, vm) in vms.iter_mut().enumerate() {
+        vm.load(program(i as i64 + 1));
+    }
+    // one instruction each, round robin, until everything is done
+    for vm in &mut vms {
+        assert_eq!(vm.step(), StepResult::Paused);
+    }
+    for vm in &mut vms {
+        assert_eq!(vm.run_for(2), StepResult::Paused);
+        assert_eq!(vm.ip, 5);
+    }
+    let results: Vec<StepResult> = vms.iter_mut().map(|vm| vm.run_for(10)).collect();
+    assert_eq!(
+        results,
+        vec![
+            StepResult::Finished(Some(Value::Int(2))),
+            StepResult::Finished(Some(Value::Int(4))),
+            StepResult::Finished(Some(Value::Int(6))),
+        ]
+    );
+    // a finished machine stays finished, without running anything
+    let vm = &mut vms[0];
+    assert_eq!(vm.step(), StepResult::Finished(Some(Value::Int(2))));
+    assert_eq!(vm.run(), InterpretResult::InterpretSuccess);
+    assert_eq!((vm.ip, vm.stack.len()), (6, 1));
+    vm.load(program(5));
+    assert_eq!(vm.run_for(10), StepResult::Finished(Some(Value::Int(10))));
+}
+
+#[test]
+fn vm_step_reports_runtime_errors() {
+    let mut b = Chunk::builder();
+    b.emit_constant(Value::Int(1));
+    b.set_line(2).emit_constant(Value::Int(0)).emit_op(OpCode::OpDivide).emit_op(OpCode::OpReturn);
+    let mut vm = VirtualMachine::init_machine();
+    vm.load(b.finish().unwrap());
+    let StepResult::Error(error) = vm.run_for(usize::MAX) else {
+        panic!("expected a runtime error");
+    };
+    assert_eq!(error.kind, ErrorKind::DivisionByZero(OpCode::OpDivide));
+    assert_eq!((error.offset, error.line), (4, Some(2)));
+    assert_eq!(error.to_string(), "division by zero in OpDivide\n[line 2] in script");
+    assert_eq!(vm.runtime_error(), Some(&error));
+}
+
+#[test]
+fn vm_interrupt_from_another_thread() {
+    // 1 + 1 + 1 ... forever
+    let mut b = Chunk::builder();
+    b.emit_constant(Value::Int(0));
+    let top = b.loop_label();
+    b.emit_constant(Value::Int(1)).emit_op(OpCode::OpAdd);
+    b.emit_loop(top);
+    let mut vm = VirtualMachine::init_machine();
+    vm.load(b.finish().unwrap());
+    // the request is made on another thread before the run starts, so the
+    // first backward jump is sure to see it
+    let handle = vm.interrupt_handle();
+    std::thread::spawn(move || handle.interrupt()).join().unwrap();
+    assert_eq!(vm.run(), InterpretResult::InterpretInterrupted);
+    // where it stopped is all the error carries
+    let error = vm.runtime_error().unwrap();
+    assert_eq!((error.kind.clone(), error.offset, error.line), (ErrorKind::Interrupted, 5, Some(1)));
+    assert_eq!(error.to_string(), "interrupted\n[line 1] in script");
+
+    // the loop carries on from where it stopped
+    let before = vm.stack[0].clone();
+    assert_eq!(vm.run_for(2), StepResult::Paused);
+    assert_eq!(vm.stack, vec![before.add(&Value::Int(1), Arithmetic::Wrapping).unwrap()]);
+    vm.interrupt_handle().interrupt();
+    assert!(matches!(vm.run_for(10), StepResult::Interrupted(_)));
+
+    // a request that no loop saw does not stop the next chunk
+    vm.interrupt_handle().interrupt();
+    let mut b = Chunk::builder();
+    b.emit_constant(Value::Int(3));
+    let top = b.loop_label();
+    b.emit_with_constant(OpCode::OpSubtractConstant, Value::Int(1)).emit_op(OpCode::OpDup);
+    let done = b.emit_jump_if_false();
+    b.emit_loop(top);
+    b.patch(done);
+    b.emit_op(OpCode::OpReturn);
+    assert_eq!(vm.interpret(b.finish().unwrap()), InterpretResult::InterpretSuccess);
+    assert_eq!(vm.stack.last(), Some(&Value::Int(0)));
+}
+
+#[test]
+fn vm_config_limits() {
+    // pushes value forever
+    let endless = |value: Value| {
+        let mut b = Chunk::builder();
+        let top = b.loop_label();
+        b.emit_constant(value);
+        b.emit_loop(top);
+        b.finish().unwrap()
+    };
+    let limited = |config: VmConfig, value: Value| {
+        let mut vm = VirtualMachine::init_machine();
+        vm.set_config(config);
+        vm.load(endless(value));
+        match vm.run_for(usize::MAX) {
+            StepResult::Error(e) => (e.kind, vm.stack.len(), vm.heap_bytes()),
+            other => panic!("expected a runtime error, got {other:?}"),
+        }
+    };
+
+    let stack = VmConfig { max_stack: 100, ..VmConfig::unlimited() };
+    assert_eq!(limited(stack, Value::Int(1)), (ErrorKind::StackOverflow, 100, 0));
+    // only big integers own heap memory, and each copy counts
+    let big = Value::Int(i64::MAX).add(&Value::Int(1), Arithmetic::Wrapping).unwrap();
+    let size = big.heap_size();
+    assert!(size > 0);
+    let heap = VmConfig { max_heap_bytes: 10 * size + 1, max_stack: 1000, ..VmConfig::unlimited() };
+    assert_eq!(limited(heap, big), (ErrorKind::OutOfMemory, 10, 10 * size));
+    assert_eq!(limited(heap, Value::Int(1)), (ErrorKind::StackOverflow, 1000, 0));
+
+    // popped values stop counting
+    let mut vm = VirtualMachine::init_machine();
+    vm.set_output(Box::new(io::sink()));
+    vm.load(compiler::compile("print 2 ** 100; 2 ** 70 - 2 ** 69").unwrap());
+    assert!(matches!(vm.run_for(usize::MAX), StepResult::Finished(Some(_))));
+    assert_eq!(vm.heap_bytes(), vm.stack()[0].heap_size());
+}
+
+#[test]
+fn vm_print_goes_to_output_and_lines_come_from_input() {
+    let out = SharedBuf::default();
+    let mut vm = VirtualMachine::init_machine();
+    vm.set_output(Box::new(out.clone()));
+    vm.set_input(Box::new(&b"first\r\nsecond"[..]));
+
+    let mut b = Chunk::builder();
+    b.emit_constant(Value::Int(7)).emit_op(OpCode::OpPrint);
+    b.emit_constant(Value::Int(8)).emit_op(OpCode::OpPrint).emit_op(OpCode::OpReturn);
+    assert_eq!(vm.interpret(b.finish().unwrap()), InterpretResult::InterpretSuccess);
+    assert_eq!(String::from_utf8(out.take()).unwrap(), "7\n8\n");
+    assert!(vm.stack().is_empty());
+
+    assert_eq!(vm.read_line().unwrap().as_deref(), Some("first"));
+    assert_eq!(vm.read_line().unwrap().as_deref(), Some("second"));
+    assert_eq!(vm.read_line().unwrap(), None);
+}
+
+#[test]
+fn vm_arithmetic_modes() {
+    use Arithmetic::*;
+    // on the u8 machine, where every mode differs;
+    // (opcode, operands, wrapping, saturating, checked); None is an overflow error
+    type Case = (OpCode, &'static [u8], u8, u8, Option<u8>);
+    let cases: [Case; 10] = [
+        (OpCode::OpNegate, &[0], 0, 0, Some(0)),
+        (OpCode::OpNegate, &[2], 254, 0, None),
+        (OpCode::OpAdd, &[200, 55], 255, 255, Some(255)),
+        (OpCode::OpAdd, &[200, 100], 44, 255, None),
+        (OpCode::OpSubtract, &[5, 3], 2, 2, Some(2)),
+        (OpCode::OpSubtract, &[3, 5], 254, 0, None),
+        (OpCode::OpMultiply, &[15, 17], 255, 255, Some(255)),
+        (OpCode::OpMultiply, &[16, 16], 0, 255, None),
+        (OpCode::OpDivide, &[255, 2], 127, 127, Some(127)),
+        (OpCode::OpModulo, &[255, 16], 15, 15, Some(15)),
+    ];
+    for (op, operands, wrapping, saturating, checked) in cases {
+        let mut b = ChunkBuilder::<u8>::default();
+        for &v in operands {
+            b.emit_constant(v);
+        }
+        b.set_line(3).emit_op(op).emit_op(OpCode::OpReturn);
+        let chunk = b.finish().unwrap();
+        for (mode, expected) in [(Wrapping, Some(wrapping)), (Saturating, Some(saturating)), (Checked, checked)] {
+            let mut vm = VmBuilder::<u8>::default().arithmetic(mode).build();
+            let result = vm.interpret(chunk.clone());
+            match expected {
+                Some(v) => {
+                    assert_eq!(result, InterpretResult::InterpretSuccess, "{op} {operands:?} {mode:?}");
+                    assert_eq!(vm.stack(), &[v], "{op} {operands:?} {mode:?}");
+                }
+                None => {
+                    assert_eq!(result, InterpretResult::InterpretRuntimeError, "{op} {operands:?} {mode:?}");
+                    let error = vm.runtime_error().unwrap();
+                    let (&right, left) = operands.split_last().unwrap();
+                    assert_eq!(error.kind, ErrorKind::Overflow { op, left: left.first().copied(), right });
+                    assert_eq!(error.line, Some(3));
+                }
+            }
+        }
+    }
+
+    // Value integers grow into bigints in every mode; only floats can overflow
+    let mut vm = VirtualMachine::builder().arithmetic(Checked).build();
+    let mut b = Chunk::builder();
+    b.emit_constant(Value::Int(i64::MAX)).emit_constant(Value::Int(1)).emit_op(OpCode::OpAdd).emit_op(OpCode::OpReturn);
+    assert_eq!(vm.interpret(b.finish().unwrap()), InterpretResult::InterpretSuccess);
+    assert_eq!(vm.stack()[0].to_string(), "9223372036854775808");
+
+    let mut b = Chunk::builder();
+    b.emit_constant(Value::Float(f64::MAX)).emit_constant(Value::Int(2)).emit_op(OpCode::OpMultiply).emit_op(OpCode::OpReturn);
+    vm.interpret(b.finish().unwrap());
+    assert_eq!(
+        vm.runtime_error().unwrap().to_string(),
+        "overflow in OpMultiply: 1.7976931348623157e308 * 2\n[line 1] in script"
+    );
+}
+
+#[test]
+fn vm_generic_over_number_types() {
+    fn eval<V: Number>(a: V, b: V, op: OpCode) -> StepResult<V> {
+        let mut builder = ChunkBuilder::<V>::default();
+        builder.emit_constant(a).emit_constant(b).emit_op(op).emit_op(OpCode::OpReturn);
+        let mut vm = VirtualMachine::<V>::new();
+        vm.load(builder.finish().unwrap());
+        vm.run_for(usize::MAX)
+    }
+
+    assert_eq!(eval(200u8, 100, OpCode::OpAdd), StepResult::Finished(Some(44)));
+    assert_eq!(eval(-7i32, 2, OpCode::OpModulo), StepResult::Finished(Some(-1)));
+    assert_eq!(eval(1i64 << 40, 1 << 20, OpCode::OpMultiply), StepResult::Finished(Some(1 << 60)));
+    assert_eq!(eval(1.0f64, 4.0, OpCode::OpDivide), StepResult::Finished(Some(0.25)));
+    assert_eq!(eval(1.0f64, 0.0, OpCode::OpDivide), StepResult::Finished(Some(f64::INFINITY)));
+    let StepResult::Error(error) = eval(1i64, 0, OpCode::OpDivide) else {
+        panic!("integer division by zero must fail");
+    };
+    assert_eq!(error.kind, ErrorKind::DivisionByZero(OpCode::OpDivide));
+    assert_eq!(eval(0xF0u8, 0x3C, OpCode::OpBitXor), StepResult::Finished(Some(0xCC)));
+    assert_eq!(eval(0x81u8, 1, OpCode::OpShl), StepResult::Finished(Some(0x02)));
+    assert_eq!(eval(0x81u8, 9, OpCode::OpShr), StepResult::Finished(Some(0)));
+    let StepResult::Error(error) = eval(3.0f64, 1.0, OpCode::OpBitAnd) else {
+        panic!("floats have no bitwise operations");
+    };
+    assert_eq!(error.kind, ErrorKind::NotAnInteger(OpCode::OpBitAnd));
+
+    let mut vm = VmBuilder::<i32>::default().arithmetic(Arithmetic::Checked).build();
+    let mut b = ChunkBuilder::default();
+    b.emit_constant(i32::MAX).emit_constant(1).emit_op(OpCode::OpAdd).emit_op(OpCode::OpReturn);
+    assert_eq!(vm.interpret(b.finish().unwrap()), InterpretResult::InterpretRuntimeError);
+    assert_eq!(vm.runtime_error().unwrap().to_string(), "overflow in OpAdd: 2147483647 + 1\n[line 1] in script");
+}
+
+#[test]
+fn vm_power_opcode() {
+    let chunk = compiler::compile_with("3 ** 4 % 5", CompileOptions::unoptimized()).unwrap();
+    assert_eq!(chunk.verify(), Ok(()));
+    let (text, next) = chunk.describe_instruction(4);
+    assert!(text.contains("OpPower"), "{text}");
+    assert!(chunk.describe_instruction(next + 2).0.contains("OpModulo"));
+
+    let mut vm = VirtualMachine::init_machine();
+    assert_eq!(vm.interpret(chunk), InterpretResult::InterpretSuccess);
+    assert_eq!(vm.stack(), &[Value::Int(1)]);
+
+    let mut vm = VmBuilder::<i32>::default().arithmetic(Arithmetic::Checked).build();
+    let mut b = ChunkBuilder::default();
+    b.emit_constant(2).emit_constant(31).emit_op(OpCode::OpPower).emit_op(OpCode::OpReturn);
+    assert_eq!(vm.interpret(b.finish().unwrap()), InterpretResult::InterpretRuntimeError);
+    assert_eq!(vm.runtime_error().unwrap().to_string(), "overflow in OpPower: 2 ** 31\n[line 1] in script");
+}
+
+#[test]
+fn vm_stack_shuffles() {
+    let shuffles = [OpCode::OpPop, OpCode::OpDup, OpCode::OpSwap, OpCode::OpOver, OpCode::OpRot];
+    for op in shuffles {
+        assert_eq!(op.mnemonic().parse::<OpCode>(), Ok(op));
+        assert_eq!(u8_to_opcode(op.byte()), Some(op));
+        assert_eq!(op.operands(), Operands::None);
+    }
+
+    // 1 2 3 -> rot -> 2 3 1 -> over -> 2 3 1 3 -> swap -> 2 3 3 1 -> dup -> 2 3 3 1 1 -> pop
+    let mut b = Chunk::builder();
+    for v in 1..=3 {
+        b.emit_constant(Value::Int(v));
+    }
+    let program = [OpCode::OpRot, OpCode::OpOver, OpCode::OpSwap, OpCode::OpDup, OpCode::OpPop];
+    for op in program {
+        b.emit_op(op);
+    }
+    b.emit_op(OpCode::OpReturn);
+    let chunk = b.finish().unwrap();
+    for (offset, op) in (6..).zip(program) {
+        let (text, next) = chunk.describe_instruction(offset);
+        assert!(text.contains(op.mnemonic()), "{text}");
+        assert_eq!(next, offset + 1);
+    }
+
+    let mut vm = VirtualMachine::init_machine();
+    assert_eq!(vm.interpret(chunk), InterpretResult::InterpretSuccess);
+    assert_eq!(vm.stack(), &[2, 3, 3, 1].map(Value::Int));
+
+    // the builder's verifier rejects underflow up front, the VM at runtime
+    let mut b = Chunk::builder();
+    b.emit_constant(Value::Int(1)).emit_op(OpCode::OpOver).emit_op(OpCode::OpReturn);
+    assert_eq!(
+        b.finish().unwrap_err().to_string(),
+        "invalid chunk: 0002: OpOver pops 2 values but the stack can hold 1"
+    );
+    let mut c = Chunk::init_chunk();
+    c.write_to_chunk(opcode_to_u8(OpCode::OpDup), 4);
+    c.write_to_chunk(opcode_to_u8(OpCode::OpReturn), 4);
+    let mut vm = VirtualMachine::init_machine();
+    assert_eq!(vm.interpret(c), InterpretResult::InterpretRuntimeError);
+    assert_eq!(vm.runtime_error().unwrap().to_string(), "stack underflow in OpDup\n[line 4] in script");
+}
+
+#[test]
+fn vm_superinstructions() {
+    for op in [OpCode::OpAddConstant, OpCode::OpSubtractConstant] {
+        assert_eq!(op.operands(), Operands::Constant);
+        assert_eq!(op.stack_effect(), StackEffect { pops: 1, pushes: 1 });
+    }
+    let mut b = Chunk::builder();
+    b.emit_constant(Value::Int(10))
+        .emit_with_constant(OpCode::OpAddConstant, Value::Int(5))
+        .emit_with_constant(OpCode::OpSubtractConstant, Value::Float(0.5))
+        .emit_op(OpCode::OpReturn);
+    let chunk = b.finish().unwrap();
+    assert!(chunk.describe_instruction(2).0.contains("OpAddConstant idx=1"));
+    let mut vm = VirtualMachine::init_machine();
+    assert_eq!(vm.interpret(chunk), InterpretResult::InterpretSuccess);
+    assert_eq!(vm.stack(), &[Value::Float(14.5)]);
+
+    // errors read as the plain operation the superinstruction replaces
+    let mut b = ChunkBuilder::<u8>::default();
+    b.emit_constant(250).set_line(3).emit_with_constant(OpCode::OpAddConstant, 10).emit_op(OpCode::OpReturn);
+    let mut vm = VmBuilder::<u8>::default().arithmetic(Arithmetic::Checked).build();
+    assert_eq!(vm.interpret(b.finish().unwrap()), InterpretResult::InterpretRuntimeError);
+    assert_eq!(vm.runtime_error().unwrap().to_string(), "overflow in OpAdd: 250 + 10\n[line 3] in script");
+
+    let mut b = Chunk::builder();
+    b.emit_with_constant(OpCode::OpAdd, Value::Int(1));
+    assert_eq!(b.finish().unwrap_err(), BuildError::NoConstantOperand { op: OpCode::OpAdd, offset: 0 });
+}
 }
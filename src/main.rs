@@ -1,51 +1,75 @@
-use rust_vm_project::{Chunk, OpCode, opcode_to_u8};
-use rust_vm_project::{VirtualMachine};
+use rust_vm_project::{Chunk, OpCode, Value};
+use rust_vm_project::{Coverage, Debugger, Profiler, VirtualMachine, compiler, dap};
 use rust_vm_project::{InterpretResult};
 use std::env;
 use std::fs;
+use std::io;
+
+// a VM with the tracing and profiling asked for by --trace and --profile,
+// whichever mode is running
+fn instrumented_machine() -> VirtualMachine {
+    let mut vm = VirtualMachine::init_machine();
+    if env::args().any(|arg| arg == "--trace") {
+        vm.set_trace(Box::new(io::stdout()));
+    }
+    if env::args().any(|arg| arg == "--profile") {
+        vm.set_profiler(Profiler::new().with_wall_clock(64));
+    }
+    vm
+}
+
+fn report_profile(vm: &mut VirtualMachine) {
+    if let Some(profiler) = vm.take_profiler() {
+        print!("{}", profiler.report());
+        let mut folded = fs::File::create("profile.folded").expect("Failed to create profile.folded");
+        profiler.write_collapsed(&mut folded).expect("Failed to write profile.folded");
+        println!("collapsed stacks written to profile.folded");
+    }
+}
 
 fn main() {
+    // the DAP stream owns stdout, so nothing else may print
+    if env::args().nth(1).as_deref() == Some("--dap") {
+        dap::serve(io::BufReader::new(io::stdin()), io::stdout()).expect("DAP connection failed");
+        return;
+    }
 
     println!("Hello, world!");
 
     println!("creating a bytecode chunk");
-    let mut chunk = Chunk::init_chunk();
-    let l = 1;
-
-    let c15 = chunk.add_constant(15);
-    chunk.write_to_chunk(opcode_to_u8(OpCode::OpConstant), l);
-    chunk.write_to_chunk(c15, l);
-
-    let c42 = chunk.add_constant(42);
-    chunk.write_to_chunk(opcode_to_u8(OpCode::OpConstant), l);
-    chunk.write_to_chunk(c42, l);
-
-    chunk.write_to_chunk(opcode_to_u8(OpCode::OpAdd), l); // 15 + 42
-     chunk.write_to_chunk(opcode_to_u8(OpCode::OpReturn), l);
+    let mut builder = Chunk::builder();
+    builder
+        .emit_constant(Value::Int(15))
+        .emit_constant(Value::Int(42))
+        .emit_op(OpCode::OpAdd) // 15 + 42
+        .emit_op(OpCode::OpReturn);
+    let chunk = builder.finish().expect("demo chunk is well formed");
 
     // Disassemble & run
     chunk.disassemble("demo chunk");
 
-    let mut vm = VirtualMachine::init_machine();
-    println!("chunk: {:?}", vm.chunk); 
-    println!("ip: {}", vm.ip);         
-    println!("stack: {:?}", vm.stack); 
+    let mut vm = instrumented_machine();
+    println!("chunk: {:?}", vm.chunk()); 
+    println!("ip: {}", vm.ip());         
+    println!("stack: {:?}", vm.stack()); 
 
     let result = vm.interpret(chunk);
     println!("Interpret result: {:?}", result);
-    println!("chunk: {:?}", vm.chunk); 
-    println!("ip: {}", vm.ip);
-    println!("stack: {:?}", vm.stack);
-    if result == InterpretResult::InterpretSuccess {
-        if let Some(top) = vm.stack.last() {
-            println!("Top of stack (expected -2) = {}", top);
-        }
+    println!("chunk: {:?}", vm.chunk()); 
+    println!("ip: {}", vm.ip());
+    println!("stack: {:?}", vm.stack());
+    report_profile(&mut vm);
+    if result == InterpretResult::InterpretSuccess
+        && let Some(top) = vm.stack().last()
+    {
+        println!("Top of stack (expected 57) = {}", top);
     }
 
 
 
-    if let Some(flag) = env::args().nth(1) {
-    if flag == "--scan" {
+    if let Some(flag) = env::args().nth(1)
+        && flag == "--scan"
+    {
         let path = env::args()
             .nth(2)
             .expect("Usage: cargo run -- --scan <file.lox>");
@@ -53,6 +77,75 @@ fn main() {
         let mut vm = VirtualMachine::init_machine();
         let result = vm.interpret_source(&source);
         println!("Interpret result: {:?}", result);
+    }
+
+    if let Some(flag) = env::args().nth(1)
+        && flag == "--run"
+    {
+        let path = env::args()
+            .nth(2)
+            .expect("Usage: cargo run -- --run <file.lox> [--trace] [--profile]");
+        let source = fs::read_to_string(&path).expect("Failed to read source file");
+        match compiler::compile(&source) {
+            Ok(chunk) => {
+                let mut vm = instrumented_machine();
+                let result = vm.interpret(chunk);
+                println!("Interpret result: {:?}", result);
+                if let Some(error) = vm.runtime_error() {
+                    eprintln!("{error}");
+                }
+                report_profile(&mut vm);
+            }
+            Err(e) => eprintln!("{e}"),
+        }
+    }
+
+    if let Some(flag) = env::args().nth(1)
+        && flag == "--coverage"
+    {
+        // every script adds to the same report
+        let mut coverage = Coverage::default();
+        let mut sources = Vec::new();
+        for path in env::args().skip(2) {
+            let source = fs::read_to_string(&path).expect("Failed to read source file");
+            match compiler::compile(&source) {
+                Ok(chunk) => {
+                    coverage.set_file(&path);
+                    let mut vm = VirtualMachine::init_machine();
+                    vm.set_coverage(coverage);
+                    let result = vm.interpret(chunk);
+                    println!("{path}: {:?}", result);
+                    coverage = vm.take_coverage().expect("coverage was set above");
+                }
+                Err(e) => eprintln!("{e}"),
+            }
+            sources.push((path, source));
+        }
+        for (path, source) in &sources {
+            println!("== {path} ==");
+            print!("{}", coverage.annotate(path, source));
+        }
+        let mut info = fs::File::create("lcov.info").expect("Failed to create lcov.info");
+        coverage.write_lcov(&mut info).expect("Failed to write lcov.info");
+        println!("coverage written to lcov.info");
+    }
+
+    if let Some(flag) = env::args().nth(1)
+        && flag == "--debug"
+    {
+        let path = env::args()
+            .nth(2)
+            .expect("Usage: cargo run -- --debug <file.lox>");
+        let source = fs::read_to_string(&path).expect("Failed to read source file");
+        match compiler::compile(&source) {
+            Ok(chunk) => {
+                let mut debugger = Debugger::new(chunk);
+                let result = debugger
+                    .run_repl(io::stdin().lock(), &mut io::stdout())
+                    .expect("Failed to talk to the terminal");
+                println!("Interpret result: {:?}", result);
+            }
+            Err(e) => eprintln!("{e}"),
         }
     }
 }
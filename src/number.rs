@@ -0,0 +1,393 @@
+use std::fmt;
+
+use crate::{Arithmetic, OpCode};
+
+// why an arithmetic operation has no result
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumberError {
+    Overflow,
+    DivisionByZero,
+    // a bitwise operation on a float
+    NotAnInteger,
+    // a shift by a negative amount
+    NegativeShift,
+}
+
+// the numeric operations a Chunk and VirtualMachine need from their value type
+// operands are borrowed so values that own heap memory are not cloned per operation
+pub trait Number: Clone + PartialEq + fmt::Debug + fmt::Display + 'static {
+    const ZERO: Self;
+
+    fn add(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError>;
+    fn sub(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError>;
+    fn mul(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError>;
+    fn div(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError>;
+    fn rem(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError>;
+    fn pow(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError>;
+    fn neg(&self, mode: Arithmetic) -> Result<Self, NumberError>;
+
+    fn bit_and(&self, rhs: &Self) -> Result<Self, NumberError>;
+    fn bit_or(&self, rhs: &Self) -> Result<Self, NumberError>;
+    fn bit_xor(&self, rhs: &Self) -> Result<Self, NumberError>;
+    fn bit_not(&self) -> Result<Self, NumberError>;
+    fn shl(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError>;
+    fn shr(&self, rhs: &Self) -> Result<Self, NumberError>;
+
+    // zero is the only falsey value
+    fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+
+    // bytes allocated on behalf of self beyond its own size, counted against
+    // VmConfig::max_heap_bytes; plain numbers allocate none
+    fn heap_size(&self) -> usize {
+        0
+    }
+
+    // appends the little-endian encoding of self
+    fn write_bytes(&self, out: &mut Vec<u8>);
+    // decodes a value from the start of bytes, and how many bytes it took
+    fn read_bytes(bytes: &[u8]) -> Option<(Self, usize)>;
+}
+
+// the result of a pure arithmetic or bitwise opcode on its operands, in
+// stack order; None when op is any other instruction or the operand count
+// does not match
+pub fn evaluate<V: Number>(op: OpCode, operands: &[V], mode: Arithmetic) -> Option<Result<V, NumberError>> {
+    let result = match (op, operands) {
+        (OpCode::OpNegate, [v]) => v.neg(mode),
+        (OpCode::OpBitNot, [v]) => v.bit_not(),
+        (OpCode::OpAdd, [a, b]) => a.add(b, mode),
+        (OpCode::OpSubtract, [a, b]) => a.sub(b, mode),
+        (OpCode::OpMultiply, [a, b]) => a.mul(b, mode),
+        (OpCode::OpDivide, [a, b]) => a.div(b, mode),
+        (OpCode::OpModulo, [a, b]) => a.rem(b, mode),
+        (OpCode::OpPower, [a, b]) => a.pow(b, mode),
+        (OpCode::OpBitAnd, [a, b]) => a.bit_and(b),
+        (OpCode::OpBitOr, [a, b]) => a.bit_or(b),
+        (OpCode::OpBitXor, [a, b]) => a.bit_xor(b),
+        (OpCode::OpShl, [a, b]) => a.shl(b, mode),
+        (OpCode::OpShr, [a, b]) => a.shr(b),
+        _ => return None,
+    };
+    Some(result)
+}
+
+// picks the wrapping, saturating or checked form of an integer method
+macro_rules! by_mode {
+    ($mode:expr, $wrapping:expr, $saturating:expr, $checked:expr) => {
+        match $mode {
+            Arithmetic::Wrapping => Ok($wrapping),
+            Arithmetic::Saturating => Ok($saturating),
+            Arithmetic::Checked => $checked.ok_or(NumberError::Overflow),
+        }
+    };
+}
+
+// division by zero is an error for every integer type; MIN / -1 overflows
+// signed types and is handled like any other overflow. a negative exponent
+// truncates like division: 1 / base**n, so only 1 and -1 survive and a zero
+// base is a division by zero.
+// a << n is a * 2**n, so bits shifted out are an overflow that wraps,
+// saturates or fails like any other; a >> n is floor(a / 2**n), which fills
+// with the sign bit. shifting by the bit width or more is not masked: it
+// moves every bit out
+macro_rules! integer_number {
+    ($($t:ty => $saturating_neg:expr, $saturating_div:expr;)*) => {$(
+        impl Number for $t {
+            const ZERO: Self = 0;
+
+            fn add(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+                let (a, b) = (*self, *rhs);
+                by_mode!(mode, a.wrapping_add(b), a.saturating_add(b), a.checked_add(b))
+            }
+
+            fn sub(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+                let (a, b) = (*self, *rhs);
+                by_mode!(mode, a.wrapping_sub(b), a.saturating_sub(b), a.checked_sub(b))
+            }
+
+            fn mul(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+                let (a, b) = (*self, *rhs);
+                by_mode!(mode, a.wrapping_mul(b), a.saturating_mul(b), a.checked_mul(b))
+            }
+
+            fn div(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+                let (a, b) = (*self, *rhs);
+                if b == 0 {
+                    return Err(NumberError::DivisionByZero);
+                }
+                let saturating: fn(Self, Self) -> Self = $saturating_div;
+                by_mode!(mode, a.wrapping_div(b), saturating(a, b), a.checked_div(b))
+            }
+
+            fn rem(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+                let (a, b) = (*self, *rhs);
+                if b == 0 {
+                    return Err(NumberError::DivisionByZero);
+                }
+                by_mode!(mode, a.wrapping_rem(b), a.wrapping_rem(b), a.checked_rem(b))
+            }
+
+            fn pow(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+                let (base, exp) = (*self, *rhs);
+                if (exp as i128) < 0 {
+                    return match base as i128 {
+                        0 => Err(NumberError::DivisionByZero),
+                        1 => Ok(1),
+                        -1 if exp % 2 == 0 => Ok(1),
+                        -1 => Ok(base),
+                        _ => Ok(0),
+                    };
+                }
+                let exp = exp as u64;
+                by_mode!(
+                    mode,
+                    pow_by_squaring(base, exp, |a, b| Some(a.wrapping_mul(b))).unwrap(),
+                    pow_by_squaring(base, exp, |a, b| Some(a.saturating_mul(b))).unwrap(),
+                    pow_by_squaring(base, exp, |a, b| a.checked_mul(b))
+                )
+            }
+
+            fn neg(&self, mode: Arithmetic) -> Result<Self, NumberError> {
+                let saturating: fn(Self) -> Self = $saturating_neg;
+                by_mode!(mode, self.wrapping_neg(), saturating(*self), self.checked_neg())
+            }
+
+            fn bit_and(&self, rhs: &Self) -> Result<Self, NumberError> {
+                Ok(self & rhs)
+            }
+
+            fn bit_or(&self, rhs: &Self) -> Result<Self, NumberError> {
+                Ok(self | rhs)
+            }
+
+            fn bit_xor(&self, rhs: &Self) -> Result<Self, NumberError> {
+                Ok(self ^ rhs)
+            }
+
+            fn bit_not(&self) -> Result<Self, NumberError> {
+                Ok(!self)
+            }
+
+            fn shl(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+                let (a, n) = (*self, *rhs as i128);
+                if n < 0 {
+                    return Err(NumberError::NegativeShift);
+                }
+                let shifted = if n >= Self::BITS as i128 { 0 } else { a << n };
+                let fits = a == 0 || (n < Self::BITS as i128 && shifted >> n == a);
+                let saturated = if (a as i128) < 0 { Self::MIN } else { Self::MAX };
+                by_mode!(mode, shifted, if fits { shifted } else { saturated }, fits.then_some(shifted))
+            }
+
+            fn shr(&self, rhs: &Self) -> Result<Self, NumberError> {
+                let (a, n) = (*self, *rhs as i128);
+                if n < 0 {
+                    return Err(NumberError::NegativeShift);
+                }
+                if n >= Self::BITS as i128 {
+                    return Ok(if (a as i128) < 0 { !0 } else { 0 });
+                }
+                Ok(a >> n)
+            }
+
+            fn write_bytes(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn read_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+                const WIDTH: usize = std::mem::size_of::<$t>();
+                Some((<$t>::from_le_bytes(bytes.get(..WIDTH)?.try_into().ok()?), WIDTH))
+            }
+        }
+    )*};
+}
+
+// square and multiply; mul returns None on overflow. the base is only
+// squared while bits of the exponent remain, so a checked mul never reports
+// an overflow the real result doesn't have
+fn pow_by_squaring<T: Copy + From<u8>>(mut base: T, mut exp: u64, mul: impl Fn(T, T) -> Option<T>) -> Option<T> {
+    let mut acc = T::from(1);
+    loop {
+        if exp & 1 == 1 {
+            acc = mul(acc, base)?;
+        }
+        exp >>= 1;
+        if exp == 0 {
+            return Some(acc);
+        }
+        base = mul(base, base)?;
+    }
+}
+
+integer_number! {
+    // unsigned negation can only saturate to zero
+    u8 => |_| 0, |a, b| a / b;
+    i32 => i32::saturating_neg, i32::saturating_div;
+    i64 => i64::saturating_neg, i64::saturating_div;
+}
+
+// floats follow IEEE 754: dividing by zero gives an infinity or NaN rather than
+// an error, and only Checked treats an infinite result from finite operands
+// as an overflow. they have no bitwise operations
+impl Number for f64 {
+    const ZERO: Self = 0.0;
+
+    fn add(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+        float_result(self + rhs, [*self, *rhs], mode)
+    }
+
+    fn sub(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+        float_result(self - rhs, [*self, *rhs], mode)
+    }
+
+    fn mul(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+        float_result(self * rhs, [*self, *rhs], mode)
+    }
+
+    fn div(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+        if *rhs == 0.0 {
+            return Ok(self / rhs);
+        }
+        float_result(self / rhs, [*self, *rhs], mode)
+    }
+
+    fn rem(&self, rhs: &Self, _mode: Arithmetic) -> Result<Self, NumberError> {
+        Ok(self % rhs)
+    }
+
+    fn pow(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+        float_result(self.powf(*rhs), [*self, *rhs], mode)
+    }
+
+    fn neg(&self, _mode: Arithmetic) -> Result<Self, NumberError> {
+        Ok(-self)
+    }
+
+    fn bit_and(&self, _rhs: &Self) -> Result<Self, NumberError> {
+        Err(NumberError::NotAnInteger)
+    }
+
+    fn bit_or(&self, _rhs: &Self) -> Result<Self, NumberError> {
+        Err(NumberError::NotAnInteger)
+    }
+
+    fn bit_xor(&self, _rhs: &Self) -> Result<Self, NumberError> {
+        Err(NumberError::NotAnInteger)
+    }
+
+    fn bit_not(&self) -> Result<Self, NumberError> {
+        Err(NumberError::NotAnInteger)
+    }
+
+    fn shl(&self, _rhs: &Self, _mode: Arithmetic) -> Result<Self, NumberError> {
+        Err(NumberError::NotAnInteger)
+    }
+
+    fn shr(&self, _rhs: &Self) -> Result<Self, NumberError> {
+        Err(NumberError::NotAnInteger)
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn read_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        Some((f64::from_le_bytes(bytes.get(..8)?.try_into().ok()?), 8))
+    }
+}
+
+fn float_result(result: f64, operands: [f64; 2], mode: Arithmetic) -> Result<f64, NumberError> {
+    match mode {
+        Arithmetic::Wrapping => Ok(result),
+        Arithmetic::Saturating if result.is_infinite() => Ok(f64::MAX.copysign(result)),
+        Arithmetic::Saturating => Ok(result),
+        Arithmetic::Checked if result.is_infinite() && operands.iter().all(|v| v.is_finite()) => {
+            Err(NumberError::Overflow)
+        }
+        Arithmetic::Checked => Ok(result),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Arithmetic::*;
+
+    fn roundtrip<V: Number>(value: V) {
+        let mut bytes = Vec::new();
+        value.write_bytes(&mut bytes);
+        assert_eq!(V::read_bytes(&bytes), Some((value, bytes.len())));
+        assert_eq!(V::read_bytes(&bytes[1..]), None);
+    }
+
+    #[test]
+    fn encodes_constants() {
+        roundtrip(200u8);
+        roundtrip(-7i32);
+        roundtrip(i64::MIN);
+        roundtrip(-0.5f64);
+    }
+
+    #[test]
+    fn division_by_zero_per_type() {
+        assert_eq!(7u8.div(&0, Wrapping), Err(NumberError::DivisionByZero));
+        assert_eq!(7i32.rem(&0, Saturating), Err(NumberError::DivisionByZero));
+        assert_eq!(7i64.div(&0, Checked), Err(NumberError::DivisionByZero));
+        assert_eq!(1.0f64.div(&0.0, Checked), Ok(f64::INFINITY));
+        assert!(0.0f64.div(&0.0, Wrapping).unwrap().is_nan());
+    }
+
+    #[test]
+    fn signed_overflow_edges() {
+        assert_eq!(i32::MIN.div(&-1, Wrapping), Ok(i32::MIN));
+        assert_eq!(i32::MIN.div(&-1, Saturating), Ok(i32::MAX));
+        assert_eq!(i32::MIN.div(&-1, Checked), Err(NumberError::Overflow));
+        assert_eq!(i64::MIN.neg(Saturating), Ok(i64::MAX));
+        assert_eq!(i64::MAX.add(&1, Checked), Err(NumberError::Overflow));
+        assert_eq!((-7i64).rem(&2, Checked), Ok(-1));
+        assert_eq!(f64::MAX.mul(&2.0, Saturating), Ok(f64::MAX));
+        assert_eq!(f64::MAX.mul(&2.0, Checked), Err(NumberError::Overflow));
+    }
+
+    #[test]
+    fn powers_per_mode() {
+        assert_eq!(Number::pow(&3u8, &5, Wrapping), Ok(243));
+        assert_eq!(Number::pow(&3u8, &6, Wrapping), Ok(217)); // 729 mod 256
+        assert_eq!(Number::pow(&3u8, &6, Saturating), Ok(255));
+        assert_eq!(Number::pow(&3u8, &6, Checked), Err(NumberError::Overflow));
+        assert_eq!(Number::pow(&0u8, &0, Checked), Ok(1));
+        assert_eq!(Number::pow(&(-2i32), &31, Checked), Ok(i32::MIN));
+        assert_eq!(Number::pow(&(-2i32), &33, Saturating), Ok(i32::MIN));
+        assert_eq!(Number::pow(&2i64, &-1, Checked), Ok(0));
+        assert_eq!(Number::pow(&(-1i64), &-3, Checked), Ok(-1));
+        assert_eq!(Number::pow(&0i32, &-1, Wrapping), Err(NumberError::DivisionByZero));
+        // more bits than a u32 exponent can hold
+        assert_eq!(Number::pow(&1i64, &(1 << 40), Checked), Ok(1));
+        assert_eq!(Number::pow(&2.0f64, &-1.0, Checked), Ok(0.5));
+        assert_eq!(Number::pow(&10.0f64, &400.0, Checked), Err(NumberError::Overflow));
+    }
+
+    #[test]
+    fn shifts_past_the_width() {
+        assert_eq!(0b1010_0000u8.bit_and(&0b1100_0000), Ok(0b1000_0000));
+        assert_eq!(0x0Fu8.bit_not(), Ok(0xF0));
+        assert_eq!(0xFFu8.shr(&7), Ok(1));
+        assert_eq!(0xFFu8.shr(&8), Ok(0));
+        assert_eq!((-8i32).shr(&1), Ok(-4));
+        assert_eq!((-8i32).shr(&40), Ok(-1));
+        assert_eq!(1i64.shl(&63, Wrapping), Ok(i64::MIN));
+        assert_eq!(1i64.shl(&64, Wrapping), Ok(0));
+        assert_eq!(0i64.shl(&1000, Checked), Ok(0));
+        assert_eq!(0x81u8.shl(&1, Wrapping), Ok(0x02));
+        assert_eq!(0x81u8.shl(&1, Saturating), Ok(0xFF));
+        assert_eq!((-3i32).shl(&31, Saturating), Ok(i32::MIN));
+        assert_eq!(1i64.shl(&63, Checked), Err(NumberError::Overflow));
+        assert_eq!(1i64.shl(&62, Checked), Ok(1 << 62));
+        assert_eq!(1i32.shl(&-1, Wrapping), Err(NumberError::NegativeShift));
+        assert_eq!(1i32.shr(&-1), Err(NumberError::NegativeShift));
+        assert_eq!(1.0f64.bit_or(&2.0), Err(NumberError::NotAnInteger));
+        assert_eq!(1.0f64.shl(&2.0, Wrapping), Err(NumberError::NotAnInteger));
+    }
+}
@@ -0,0 +1,441 @@
+use std::collections::HashSet;
+
+use crate::number::evaluate;
+use crate::verifier::jump_target;
+use crate::{Arithmetic, Chunk, Number, OpCode, Operands, VerifyError};
+
+impl<V: Number> Chunk<V> {
+    pub fn optimize(&mut self, mode: Arithmetic) -> Result<(), VerifyError> {
+        optimize(self, mode)
+    }
+}
+
+// one decoded instruction; a jump's operand is the index of its target
+// instruction and a constant's an index into the working constant list, so
+// instructions can come and go without recomputing byte offsets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Instruction {
+    op: OpCode,
+    operand: usize,
+    line: u8,
+}
+
+// rewrites a verified chunk in place into a shorter one that behaves the
+// same when run with the given arithmetic mode:
+// - OpNegate OpNegate pairs go, in Wrapping mode only, since a saturating or
+//   checked negation is not its own inverse
+// - constants followed by the arithmetic or bitwise op that consumes them
+//   become one constant, unless the op fails and has to stay for the VM to report
+// - a jump to an unconditional jump goes straight to the end of the chain
+// - code no path reaches, such as anything after an OpReturn, is dropped
+// - a constant followed by OpAdd or OpSubtract becomes OpAddConstant or
+//   OpSubtractConstant once nothing else is left to rewrite
+// every remaining instruction keeps its line, and a fused constant takes the
+// line of its operator. an invalid chunk is left alone and its error returned
+pub fn optimize<V: Number>(chunk: &mut Chunk<V>, mode: Arithmetic) -> Result<(), VerifyError> {
+    chunk.verify()?;
+    let mut values = chunk.values.clone();
+    let mut code = decode(chunk);
+    // each rewrite can expose another, e.g. fusing 1 + 2 makes (1 + 2) * 3 fusable
+    loop {
+        let before = code.clone();
+        collapse_jump_chains(&mut code);
+        remove_unreachable(&mut code);
+        fuse(&mut code, &mut values, mode);
+        if code == before {
+            break;
+        }
+    }
+    superinstructions(&mut code);
+    encode(chunk, &code, &values);
+    debug_assert_eq!(chunk.verify(), Ok(()));
+    Ok(())
+}
+
+fn is_jump(op: OpCode) -> bool {
+    matches!(op.operands(), Operands::Jump | Operands::Loop)
+}
+
+fn decode<V: Number>(chunk: &Chunk<V>) -> Vec<Instruction> {
+    let mut index_at = vec![0; chunk.code.len()];
+    let mut code = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = OpCode::from_byte(chunk.code[offset]).expect("the chunk was verified");
+        let operand = match op.operands() {
+            Operands::None => 0,
+            Operands::Constant => chunk.code[offset + 1] as usize,
+            // a byte offset until every instruction has an index
+            Operands::Jump | Operands::Loop => jump_target(chunk, offset, op) as usize,
+        };
+        index_at[offset] = code.len();
+        code.push(Instruction { op, operand, line: chunk.lines[offset] });
+        offset += 1 + op.operands().width();
+    }
+    for instruction in code.iter_mut().filter(|i| is_jump(i.op)) {
+        instruction.operand = index_at[instruction.operand];
+    }
+    code
+}
+
+// byte offset of every instruction, then the length of the code
+fn offsets(code: &[Instruction]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(code.len() + 1);
+    let mut offset = 0;
+    for instruction in code {
+        offsets.push(offset);
+        offset += 1 + instruction.op.operands().width();
+    }
+    offsets.push(offset);
+    offsets
+}
+
+fn encode<V: Number>(chunk: &mut Chunk<V>, code: &[Instruction], values: &[V]) {
+    let offsets = offsets(code);
+    // constants are renumbered in order of use and unused ones dropped; fuse
+    // never leaves more in use than a byte can name
+    let mut renumbered: Vec<Option<u8>> = vec![None; values.len()];
+    let mut pool = Vec::new();
+    chunk.code.clear();
+    chunk.lines.clear();
+    for (i, instruction) in code.iter().enumerate() {
+        let line = instruction.line;
+        match instruction.op.operands() {
+            Operands::None => chunk.write_to_chunk(instruction.op.byte(), line),
+            Operands::Constant => {
+                let index = *renumbered[instruction.operand].get_or_insert_with(|| {
+                    pool.push(values[instruction.operand].clone());
+                    u8::try_from(pool.len() - 1).expect("fuse keeps at most 256 constants in use")
+                });
+                chunk.write_to_chunk(instruction.op.byte(), line);
+                chunk.write_to_chunk(index, line);
+            }
+            Operands::Jump | Operands::Loop => {
+                let (next, target) = (offsets[i] + 3, offsets[instruction.operand]);
+                // unconditional jumps point whichever way their target now is
+                let (op, distance) = match instruction.op {
+                    OpCode::OpJumpIfFalse => (OpCode::OpJumpIfFalse, target - next),
+                    _ if target >= next => (OpCode::OpJump, target - next),
+                    _ => (OpCode::OpLoop, next - target),
+                };
+                let [hi, lo] = u16::try_from(distance).expect("rewrites never stretch a jump past u16").to_be_bytes();
+                chunk.write_to_chunk(op.byte(), line);
+                chunk.write_to_chunk(hi, line);
+                chunk.write_to_chunk(lo, line);
+            }
+        }
+    }
+    chunk.values = pool;
+}
+
+// drops the instructions marked dead; a jump to a dropped instruction moves
+// on to the next one that stays
+fn retain(code: &mut Vec<Instruction>, dead: &[bool]) {
+    let mut new_index = Vec::with_capacity(code.len() + 1);
+    let mut kept = 0;
+    for &is_dead in dead {
+        new_index.push(kept);
+        kept += usize::from(!is_dead);
+    }
+    new_index.push(kept);
+    let mut i = 0;
+    code.retain(|_| {
+        i += 1;
+        !dead[i - 1]
+    });
+    for instruction in code.iter_mut().filter(|i| is_jump(i.op)) {
+        instruction.operand = new_index[instruction.operand];
+    }
+}
+
+fn collapse_jump_chains(code: &mut [Instruction]) {
+    let offsets = offsets(code);
+    for i in 0..code.len() {
+        if !is_jump(code[i].op) {
+            continue;
+        }
+        let mut target = code[i].operand;
+        let mut seen = vec![i];
+        while matches!(code[target].op, OpCode::OpJump | OpCode::OpLoop) && !seen.contains(&target) {
+            seen.push(target);
+            target = code[target].operand;
+        }
+        // OpJumpIfFalse only jumps forward, and no jump can outgrow its operand
+        let forward = target > i;
+        let distance = offsets[target].abs_diff(offsets[i] + 3);
+        if (forward || code[i].op != OpCode::OpJumpIfFalse) && distance <= u16::MAX as usize {
+            code[i].operand = target;
+        }
+    }
+}
+
+fn remove_unreachable(code: &mut Vec<Instruction>) {
+    let mut reached = vec![false; code.len()];
+    let mut pending = vec![0];
+    while let Some(i) = pending.pop() {
+        if reached[i] {
+            continue;
+        }
+        reached[i] = true;
+        let instruction = code[i];
+        match instruction.op {
+            OpCode::OpReturn => {}
+            OpCode::OpJump | OpCode::OpLoop => pending.push(instruction.operand),
+            OpCode::OpJumpIfFalse => pending.extend([instruction.operand, i + 1]),
+            _ => pending.push(i + 1),
+        }
+    }
+    let dead: Vec<bool> = reached.iter().map(|&r| !r).collect();
+    retain(code, &dead);
+}
+
+fn jump_targets(code: &[Instruction]) -> HashSet<usize> {
+    code.iter().filter(|i| is_jump(i.op)).map(|i| i.operand).collect()
+}
+
+fn fuse<V: Number>(code: &mut Vec<Instruction>, values: &mut Vec<V>, mode: Arithmetic) {
+    let targets = jump_targets(code);
+    // a sequence can only be rewritten if nothing jumps into its middle
+    let entered = |start: usize, len: usize| (start + 1..start + len).any(|j| targets.contains(&j));
+    let mut uses = vec![0; values.len()];
+    for instruction in code.iter().filter(|i| i.op.operands() == Operands::Constant) {
+        uses[instruction.operand] += 1;
+    }
+    let mut dead = vec![false; code.len()];
+    let mut i = 0;
+    while i < code.len() {
+        if mode == Arithmetic::Wrapping
+            && code[i].op == OpCode::OpNegate
+            && code.get(i + 1).is_some_and(|next| next.op == OpCode::OpNegate)
+            && !entered(i, 2)
+        {
+            dead[i..i + 2].fill(true);
+            i += 2;
+            continue;
+        }
+        if let Some((len, value)) = constant_result(&code[i..], values, mode)
+            && !entered(i, len)
+            && let Some(index) = intern(values, &mut uses, &code[i..i + len - 1], value)
+        {
+            let line = code[i + len - 1].line;
+            code[i] = Instruction { op: OpCode::OpConstant, operand: index, line };
+            dead[i + 1..i + len].fill(true);
+            i += len;
+            continue;
+        }
+        i += 1;
+    }
+    retain(code, &dead);
+}
+
+// trades the uses of the folded constants for one use of value, and returns
+// its index. an equal value already in the list is reused; compared through
+// Debug, so NaN finds NaN and -0.0 is not taken for 0.0. none, with the uses
+// unchanged, if the fold would leave more than 256 constants in use
+fn intern<V: Number>(values: &mut Vec<V>, uses: &mut Vec<usize>, folded: &[Instruction], value: V) -> Option<usize> {
+    for instruction in folded {
+        uses[instruction.operand] -= 1;
+    }
+    let wanted = format!("{value:?}");
+    let index = values.iter().position(|v| format!("{v:?}") == wanted);
+    let in_use = uses.iter().filter(|&&n| n > 0).count();
+    if index.is_none_or(|i| uses[i] == 0) && in_use > u8::MAX as usize {
+        for instruction in folded {
+            uses[instruction.operand] += 1;
+        }
+        return None;
+    }
+    let index = index.unwrap_or_else(|| {
+        values.push(value);
+        uses.push(0);
+        values.len() - 1
+    });
+    uses[index] += 1;
+    Some(index)
+}
+
+// the value of constants at the start of code and the operator right after
+// them, with how many instructions that covers
+fn constant_result<V: Number>(code: &[Instruction], values: &[V], mode: Arithmetic) -> Option<(usize, V)> {
+    let constant = |i: &Instruction| (i.op == OpCode::OpConstant).then(|| values[i.operand].clone());
+    let (len, operands) = match code {
+        [a, b, op, ..] if op.op.stack_effect().pops == 2 => (3, vec![constant(a)?, constant(b)?]),
+        [a, op, ..] if op.op.stack_effect().pops == 1 => (2, vec![constant(a)?]),
+        _ => return None,
+    };
+    let value = evaluate(code[len - 1].op, &operands, mode)?.ok()?;
+    Some((len, value))
+}
+
+// the superinstruction takes the operator's line, as a fused constant does. a
+// jump to the constant now lands on the superinstruction, and one to the
+// operator keeps the pair apart
+fn superinstructions(code: &mut Vec<Instruction>) {
+    let targets = jump_targets(code);
+    let mut dead = vec![false; code.len()];
+    for i in 1..code.len() {
+        let op = match code[i].op {
+            OpCode::OpAdd => OpCode::OpAddConstant,
+            OpCode::OpSubtract => OpCode::OpSubtractConstant,
+            _ => continue,
+        };
+        if code[i - 1].op == OpCode::OpConstant && !targets.contains(&i) {
+            code[i] = Instruction { op, operand: code[i - 1].operand, line: code[i].line };
+            dead[i - 1] = true;
+        }
+    }
+    retain(code, &dead);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{CompileOptions, compile_with};
+    use crate::output::SharedBuf;
+    use crate::testing::{assert_same, countdown};
+    use crate::{ErrorKind, InterpretResult, Value, VmBuilder, VmConfig};
+
+    // what running a chunk produced, minus where in the bytecode it happened
+    type Outcome<V> = (InterpretResult, Vec<V>, Option<(ErrorKind<V>, Option<u8>)>, String);
+
+    fn run<V: Number>(chunk: Chunk<V>, mode: Arithmetic) -> Outcome<V> {
+        let config = VmConfig { arithmetic: mode, ..VmConfig::default() };
+        let printed = SharedBuf::default();
+        let mut vm = VmBuilder::<V>::default().config(config).fuel(100_000).output(Box::new(printed.clone())).build();
+        let result = vm.interpret(chunk);
+        let error = vm.runtime_error().map(|e| (e.kind.clone(), e.line));
+        // the optimized chunk gets further on the same fuel, so only the
+        // fact that it ran out is comparable
+        match result {
+            InterpretResult::InterpretOutOfFuel => (result, Vec::new(), error, String::new()),
+            _ => (result, vm.stack().to_vec(), error, String::from_utf8(printed.take()).unwrap()),
+        }
+    }
+
+    // runs the chunk before and after optimizing it, and returns the optimized one
+    fn same_behaviour<V: Number>(chunk: &Chunk<V>, mode: Arithmetic) -> Chunk<V> {
+        let mut optimized = chunk.clone();
+        optimized.optimize(mode).unwrap();
+        assert!(optimized.len() <= chunk.len());
+        let (after, before) = (run(optimized.clone(), mode), run(chunk.clone(), mode));
+        assert_same(&after, &before, format_args!("mode {mode:?}"));
+        optimized
+    }
+
+    // xorshift, so the generated programs are the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    fn expression(rng: &mut Rng, depth: u32) -> String {
+        const BINARY: [&str; 11] = ["+", "-", "*", "/", "%", "**", "&", "|", "^", "<<", ">>"];
+        if depth == 0 || rng.below(4) == 0 {
+            return match rng.below(6) {
+                0 => "0".to_string(),
+                1 => format!("{}.5", rng.below(10)),
+                2 => "9223372036854775807".to_string(),
+                _ => rng.below(70).to_string(),
+            };
+        }
+        match rng.below(5) {
+            0 => format!("-({})", expression(rng, depth - 1)),
+            1 => format!("~({})", expression(rng, depth - 1)),
+            _ => {
+                let op = BINARY[rng.below(BINARY.len() as u64) as usize];
+                format!("({} {op}\n{})", expression(rng, depth - 1), expression(rng, depth - 1))
+            }
+        }
+    }
+
+    #[test]
+    fn differential_on_generated_expressions() {
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        for _ in 0..300 {
+            let source = expression(&mut rng, 4);
+            let chunk = compile_with(&source, CompileOptions::unoptimized()).unwrap();
+            for mode in [Arithmetic::Wrapping, Arithmetic::Saturating, Arithmetic::Checked] {
+                same_behaviour(&chunk, mode);
+            }
+        }
+    }
+
+    #[test]
+    fn differential_on_control_flow() {
+        for n in [0i64, 1, 7, 250] {
+            let chunk = countdown(Value::Int(n), Value::Int(1));
+            let optimized = same_behaviour(&chunk, Arithmetic::Wrapping);
+            // the shuffles, then subtract the step and jump straight back to
+            // the top; the negations and dead code are gone
+            let ops: Vec<OpCode> = decode(&optimized).iter().map(|i| i.op).collect();
+            use OpCode::*;
+            assert_eq!(
+                ops,
+                [
+                    OpConstant, OpDup, OpPrint, OpDup, OpJumpIfFalse, OpConstant, OpSwap, OpOver, OpRot, OpPop, OpPop,
+                    OpSubtractConstant, OpLoop, OpReturn
+                ]
+            );
+            assert_eq!(optimized.values, [n, 1, 1].map(Value::Int));
+            assert_eq!(optimized.lines.last(), Some(&4));
+
+            // saturating negation is not an involution, so the pair stays
+            let chunk = countdown(n as i32, 1);
+            let optimized = same_behaviour(&chunk, Arithmetic::Saturating);
+            assert_eq!(decode(&optimized).iter().filter(|i| i.op == OpCode::OpNegate).count(), 2);
+        }
+        // an endless countdown runs out of fuel the same way in both
+        same_behaviour(&countdown(Value::Int(1), Value::Int(2)), Arithmetic::Wrapping);
+        same_behaviour(&countdown(3u8, 2), Arithmetic::Checked);
+    }
+
+    // every constant is negated once and inverted once, each fold a new value
+    fn negated_and_inverted(n: i64, scale: i64) -> Chunk<Value> {
+        let mut chunk = Chunk::init_chunk();
+        for i in 1..=n {
+            let index = chunk.add_constant(Value::Int(i * scale));
+            for op in [OpCode::OpNegate, OpCode::OpBitNot] {
+                chunk.write_to_chunk(OpCode::OpConstant.byte(), 1);
+                chunk.write_to_chunk(index, 1);
+                chunk.write_to_chunk(op.byte(), 1);
+            }
+        }
+        chunk.write_to_chunk(OpCode::OpReturn.byte(), 1);
+        chunk
+    }
+
+    #[test]
+    fn keeps_constants_within_an_operand_byte() {
+        // -i and ~(i - 1) are the same value, so 300 folds need only 151 constants
+        let optimized = same_behaviour(&negated_and_inverted(150, 1), Arithmetic::Wrapping);
+        assert!(decode(&optimized).iter().all(|i| matches!(i.op, OpCode::OpConstant | OpCode::OpReturn)));
+        assert_eq!(optimized.values.len(), 151);
+
+        // 300 distinct results: folds stop once 256 constants are in use
+        let optimized = same_behaviour(&negated_and_inverted(150, 1000), Arithmetic::Wrapping);
+        assert_eq!(optimized.values.len(), 256);
+        assert!(decode(&optimized).iter().any(|i| i.op == OpCode::OpBitNot));
+    }
+
+    #[test]
+    fn fuses_only_what_cannot_fail() {
+        let chunk = compile_with("1 + 2 * 3 - 4 / 0\n+ (5 % 3)", CompileOptions::unoptimized()).unwrap();
+        let optimized = same_behaviour(&chunk, Arithmetic::Wrapping);
+        let ops: Vec<OpCode> = decode(&optimized).iter().map(|i| i.op).collect();
+        use OpCode::*;
+        assert_eq!(ops, [OpConstant, OpConstant, OpConstant, OpDivide, OpSubtract, OpAddConstant, OpReturn]);
+        assert_eq!(optimized.values, [7, 4, 0, 2].map(Value::Int));
+        assert_eq!(optimized.lines[optimized.len() - 2], 2);
+
+        let mut invalid = Chunk::init_chunk();
+        invalid.write_to_chunk(OpCode::OpAdd.byte(), 1);
+        assert!(invalid.optimize(Arithmetic::Wrapping).is_err());
+        assert_eq!(invalid.code, [OpCode::OpAdd.byte()]);
+    }
+}
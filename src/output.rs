@@ -0,0 +1,26 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+// a print or trace sink whose bytes can still be read after a VM has taken
+// it; clones share the same buffer
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuf {
+    // hands back what was written and empties the buffer
+    pub(crate) fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.borrow_mut())
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::{Chunk, OpCode, u8_to_opcode};
+
+// name reported for top-level code; the VM has no functions yet, so every
+// instruction belongs to it
+const SCRIPT: &str = "script";
+
+// counts executed instructions per opcode, bytecode offset and source line
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    opcodes: [u64; 256],
+    offsets: Vec<u64>,
+    lines: BTreeMap<u8, u64>,
+    // (line, opcode) pairs, for collapsed stacks
+    leaves: BTreeMap<(u8, u8), u64>,
+    total: u64,
+    sampling: Option<Sampling>,
+}
+
+// wall-clock time is read every `every` instructions and charged to the
+// function running at that moment
+#[derive(Debug, Clone)]
+struct Sampling {
+    every: u32,
+    countdown: u32,
+    last: Option<Instant>,
+    functions: BTreeMap<&'static str, Duration>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler {
+            opcodes: [0; 256],
+            offsets: Vec::new(),
+            lines: BTreeMap::new(),
+            leaves: BTreeMap::new(),
+            total: 0,
+            sampling: None,
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    // also sample wall-clock time per function every `every` instructions
+    pub fn with_wall_clock(mut self, every: u32) -> Self {
+        let every = every.max(1);
+        self.sampling = Some(Sampling {
+            every,
+            countdown: every,
+            last: None,
+            functions: BTreeMap::new(),
+        });
+        self
+    }
+
+    pub(crate) fn record<V>(&mut self, chunk: &Chunk<V>, offset: usize) {
+        let byte = chunk.code[offset];
+        let line = chunk.lines[offset];
+        self.total += 1;
+        self.opcodes[byte as usize] += 1;
+        if self.offsets.len() < chunk.code.len() {
+            self.offsets.resize(chunk.code.len(), 0);
+        }
+        self.offsets[offset] += 1;
+        *self.lines.entry(line).or_default() += 1;
+        *self.leaves.entry((line, byte)).or_default() += 1;
+
+        if let Some(s) = self.sampling.as_mut() {
+            s.countdown -= 1;
+            if s.countdown == 0 {
+                s.countdown = s.every;
+                let now = Instant::now();
+                if let Some(last) = s.last {
+                    *s.functions.entry(SCRIPT).or_default() += now - last;
+                }
+                s.last = Some(now);
+            }
+        }
+    }
+
+    pub fn total_instructions(&self) -> u64 {
+        self.total
+    }
+
+    // executed opcodes, most frequent first
+    pub fn opcode_counts(&self) -> Vec<(OpCode, u64)> {
+        let mut counts: Vec<_> = self
+            .opcodes
+            .iter()
+            .enumerate()
+            .filter(|&(_, &n)| n > 0)
+            .filter_map(|(byte, &n)| Some((u8_to_opcode(byte as u8)?, n)))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.byte().cmp(&b.0.byte())));
+        counts
+    }
+
+    // executed bytecode offsets, most frequent first
+    pub fn offset_counts(&self) -> Vec<(usize, u64)> {
+        let mut counts: Vec<_> = self
+            .offsets
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, n)| n > 0)
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+
+    // executed source lines, most frequent first
+    pub fn line_counts(&self) -> Vec<(u8, u64)> {
+        let mut counts: Vec<_> = self.lines.iter().map(|(&l, &n)| (l, n)).collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+
+    // sampled wall-clock time per function, empty unless with_wall_clock was used
+    pub fn function_times(&self) -> Vec<(&'static str, Duration)> {
+        let Some(s) = &self.sampling else {
+            return Vec::new();
+        };
+        let mut times: Vec<_> = s.functions.iter().map(|(&f, &d)| (f, d)).collect();
+        times.sort_by_key(|&(_, d)| std::cmp::Reverse(d));
+        times
+    }
+
+    pub fn report(&self) -> String {
+        let total = self.total.max(1) as f64;
+        let mut out = String::new();
+        let _ = writeln!(out, "== profile: {} instructions ==", self.total);
+
+        let _ = writeln!(out, "-- by opcode --");
+        for (op, n) in self.opcode_counts() {
+            let _ = writeln!(out, "{:<14} {:>10} {:>6.1}%", op, n, n as f64 * 100.0 / total);
+        }
+        let _ = writeln!(out, "-- by line --");
+        for (line, n) in self.line_counts() {
+            let _ = writeln!(out, "line {:>4}      {:>10} {:>6.1}%", line, n, n as f64 * 100.0 / total);
+        }
+        let _ = writeln!(out, "-- by offset --");
+        for (offset, n) in self.offset_counts() {
+            let _ = writeln!(out, "{:04}           {:>10} {:>6.1}%", offset, n, n as f64 * 100.0 / total);
+        }
+        let times = self.function_times();
+        if !times.is_empty() {
+            let _ = writeln!(out, "-- wall clock by function --");
+            for (function, time) in times {
+                let _ = writeln!(out, "{:<14} {:>10.3}ms", function, time.as_secs_f64() * 1000.0);
+            }
+        }
+        out
+    }
+
+    // one `frame;frame;frame count` line per stack, as flamegraph tools expect
+    pub fn write_collapsed(&self, out: &mut impl Write) -> io::Result<()> {
+        for (&(line, byte), &n) in &self.leaves {
+            let op = u8_to_opcode(byte).map_or_else(|| format!("0x{byte:02X}"), |op| op.to_string());
+            writeln!(out, "{SCRIPT};line {line};{op} {n}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InterpretResult, Value, VirtualMachine};
+
+    fn profiled_run() -> Profiler {
+        // 1 + 2 on line 1, a taken jump over a 9 on line 2, return on line 3
+        let mut b = Chunk::builder();
+        b.emit_constant(Value::Int(1)).emit_constant(Value::Int(2)).emit_op(OpCode::OpAdd);
+        b.set_line(2).emit_constant(Value::Int(0));
+        let skip = b.emit_jump_if_false();
+        b.emit_constant(Value::Int(9));
+        b.patch(skip);
+        b.set_line(3).emit_op(OpCode::OpReturn);
+        let chunk = b.finish().unwrap();
+
+        let mut vm = VirtualMachine::init_machine();
+        vm.set_profiler(Profiler::new().with_wall_clock(1));
+        assert_eq!(vm.interpret(chunk), InterpretResult::InterpretSuccess);
+        vm.take_profiler().unwrap()
+    }
+
+    #[test]
+    fn counts_opcodes_offsets_and_lines() {
+        let p = profiled_run();
+        let ops = p.opcode_counts();
+        assert_eq!(ops[0], (OpCode::OpConstant, 3));
+        assert!(ops.contains(&(OpCode::OpReturn, 1)));
+        assert!(ops.contains(&(OpCode::OpJumpIfFalse, 1)));
+        assert_eq!(p.total_instructions(), 6);
+        assert_eq!(p.line_counts().iter().find(|(l, _)| *l == 3), Some(&(3, 1)));
+        assert_eq!(p.offset_counts().iter().map(|(_, n)| n).sum::<u64>(), p.total_instructions());
+        assert_eq!(p.function_times()[0].0, "script");
+
+        let report = p.report();
+        assert!(report.starts_with(&format!("== profile: {} instructions ==", p.total_instructions())));
+        assert!(report.contains("OpConstant"));
+    }
+
+    #[test]
+    fn collapsed_stacks_sum_to_total() {
+        let p = profiled_run();
+        let mut out = Vec::new();
+        p.write_collapsed(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.lines().any(|l| l == "script;line 3;OpReturn 1"));
+        let sum: u64 = text
+            .lines()
+            .map(|l| l.rsplit_once(' ').unwrap().1.parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(sum, p.total_instructions());
+    }
+}
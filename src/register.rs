@@ -0,0 +1,566 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::verifier::jump_target;
+use crate::{
+    Arithmetic, Chunk, ErrorKind, InterruptHandle, InterpretResult, Number, OpCode, Operands, RuntimeError, Value,
+    VerifyError, VirtualMachine, VmConfig,
+};
+
+// a three-address instruction; registers are numbered like the stack slots
+// they stand for, r0 at the bottom, plus one scratch register past the deepest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Load { dst: usize, constant: usize }, // pushes a constant
+    Copy { dst: usize, src: usize },      // pushes a copy of a register
+    Move { dst: usize, src: usize },      // overwrites a register, for shuffles
+    Unary { op: OpCode, dst: usize, src: usize },
+    Binary { op: OpCode, dst: usize, left: usize, right: usize },
+    BinaryConstant { op: OpCode, dst: usize, left: usize, constant: usize },
+    Jump { target: usize },
+    JumpIfFalse { cond: usize, target: usize },
+    Print { src: usize },
+    Pop { src: usize }, // lets go of the top value; the register keeps it
+    Return,
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Load { dst, constant } => write!(f, "r{dst} = k{constant}"),
+            Instruction::Copy { dst, src } | Instruction::Move { dst, src } => write!(f, "r{dst} = r{src}"),
+            Instruction::Unary { op, dst, src } => write!(f, "r{dst} = {op} r{src}"),
+            Instruction::Binary { op, dst, left, right } => write!(f, "r{dst} = {op} r{left}, r{right}"),
+            Instruction::BinaryConstant { op, dst, left, constant } => write!(f, "r{dst} = {op} r{left}, k{constant}"),
+            Instruction::Jump { target } => write!(f, "jump {target:04}"),
+            Instruction::JumpIfFalse { cond, target } => write!(f, "jump {target:04} if r{cond} is zero"),
+            Instruction::Print { src } => write!(f, "print r{src}"),
+            Instruction::Pop { src } => write!(f, "pop r{src}"),
+            Instruction::Return => write!(f, "return"),
+        }
+    }
+}
+
+// where a register instruction came from, so errors read as they would on
+// the stack machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Site {
+    offset: usize, // of the stack instruction
+    line: u8,
+    live: usize, // registers in use if the instruction stops the run
+}
+
+// why a stack chunk has no register form
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranslateError {
+    Invalid(VerifyError),
+    // paths reach offset with different stack depths, so its operands
+    // would not live in fixed registers
+    UnbalancedStack { offset: usize, depths: (usize, usize) },
+}
+
+impl fmt::Display for TranslateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranslateError::Invalid(e) => write!(f, "invalid chunk: {e}"),
+            TranslateError::UnbalancedStack { offset, depths: (a, b) } => {
+                write!(f, "{offset:04}: reached with {a} and with {b} values on the stack")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TranslateError {}
+
+// a stack chunk translated to register code
+#[derive(Debug, Clone)]
+pub struct RegisterChunk<V = Value> {
+    code: Vec<Instruction>,
+    sites: Vec<Site>,
+    values: Vec<V>,
+    registers: usize,
+}
+
+impl<V: Number> RegisterChunk<V> {
+    pub fn code(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    // registers a run needs, counting the scratch register
+    pub fn registers(&self) -> usize {
+        self.registers
+    }
+}
+
+impl<V: Number> Chunk<V> {
+    pub fn to_registers(&self) -> Result<RegisterChunk<V>, TranslateError> {
+        translate(self)
+    }
+}
+
+// translates a verified chunk whose stack depth at each instruction is the
+// same on every path that reaches it. the slot an instruction's operand
+// occupies then names its register, and each stack instruction becomes
+// the register instructions that do the same to those slots:
+// - OpPop becomes Pop, which reads nothing but stops counting the value's
+//   heap bytes
+// - OpSwap and OpRot move values through the scratch register
+// - code no path reaches is left out
+pub fn translate<V: Number>(chunk: &Chunk<V>) -> Result<RegisterChunk<V>, TranslateError> {
+    chunk.verify().map_err(TranslateError::Invalid)?;
+    let depths = depths(chunk)?;
+    let deepest = depths.iter().flatten().max().copied().unwrap_or(0);
+    let scratch = deepest + 1;
+
+    // index of the first register instruction at each offset, plus the end
+    let mut index_at = vec![0; chunk.code.len() + 1];
+    let mut jumps = Vec::new();
+    let mut code = Vec::new();
+    let mut sites = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        index_at[offset] = code.len();
+        let op = OpCode::from_byte(chunk.code[offset]).expect("the chunk was verified");
+        let next = offset + 1 + op.operands().width();
+        let Some(depth) = depths[offset] else {
+            offset = next;
+            continue;
+        };
+        // only read by instructions the verifier knows have that many operands
+        let (top, second) = (depth.wrapping_sub(1), depth.wrapping_sub(2));
+        let constant = || chunk.code[offset + 1] as usize;
+        let emitted: &[Instruction] = match op {
+            OpCode::OpReturn => &[Instruction::Return],
+            OpCode::OpConstant => &[Instruction::Load { dst: depth, constant: constant() }],
+            OpCode::OpNegate | OpCode::OpBitNot => &[Instruction::Unary { op, dst: top, src: top }],
+            OpCode::OpAddConstant => {
+                &[Instruction::BinaryConstant { op: OpCode::OpAdd, dst: top, left: top, constant: constant() }]
+            }
+            OpCode::OpSubtractConstant => {
+                &[Instruction::BinaryConstant { op: OpCode::OpSubtract, dst: top, left: top, constant: constant() }]
+            }
+            OpCode::OpPop => &[Instruction::Pop { src: top }],
+            OpCode::OpDup => &[Instruction::Copy { dst: depth, src: top }],
+            OpCode::OpOver => &[Instruction::Copy { dst: depth, src: second }],
+            OpCode::OpSwap => &[
+                Instruction::Move { dst: scratch, src: second },
+                Instruction::Move { dst: second, src: top },
+                Instruction::Move { dst: top, src: scratch },
+            ],
+            OpCode::OpRot => &[
+                Instruction::Move { dst: scratch, src: depth - 3 },
+                Instruction::Move { dst: depth - 3, src: second },
+                Instruction::Move { dst: second, src: top },
+                Instruction::Move { dst: top, src: scratch },
+            ],
+            // targets are offsets until every instruction has an index
+            OpCode::OpJump | OpCode::OpLoop => {
+                &[Instruction::Jump { target: jump_target(chunk, offset, op) as usize }]
+            }
+            OpCode::OpJumpIfFalse => {
+                &[Instruction::JumpIfFalse { cond: top, target: jump_target(chunk, offset, op) as usize }]
+            }
+            OpCode::OpPrint => &[Instruction::Print { src: top }],
+            OpCode::OpAdd
+            | OpCode::OpSubtract
+            | OpCode::OpMultiply
+            | OpCode::OpDivide
+            | OpCode::OpModulo
+            | OpCode::OpPower
+            | OpCode::OpBitAnd
+            | OpCode::OpBitOr
+            | OpCode::OpBitXor
+            | OpCode::OpShl
+            | OpCode::OpShr => &[Instruction::Binary { op, dst: second, left: second, right: top }],
+        };
+        // OpDup and OpOver only read what they copy, so a failed push leaves it
+        let popped = match op {
+            OpCode::OpDup | OpCode::OpOver => 0,
+            _ => op.stack_effect().pops,
+        };
+        let site = Site { offset, line: chunk.lines[offset], live: depth - popped };
+        for &instruction in emitted {
+            if matches!(op.operands(), Operands::Jump | Operands::Loop) {
+                jumps.push(code.len());
+            }
+            code.push(instruction);
+            sites.push(site);
+        }
+        offset = next;
+    }
+    index_at[chunk.code.len()] = code.len();
+    for i in jumps {
+        if let Instruction::Jump { target } | Instruction::JumpIfFalse { target, .. } = &mut code[i] {
+            *target = index_at[*target];
+        }
+    }
+    Ok(RegisterChunk { code, sites, values: chunk.values.clone(), registers: scratch + 1 })
+}
+
+// the stack depth at every reachable instruction, None for the rest
+fn depths<V: Number>(chunk: &Chunk<V>) -> Result<Vec<Option<usize>>, TranslateError> {
+    let mut depths: Vec<Option<usize>> = vec![None; chunk.code.len()];
+    let mut pending = vec![(0usize, 0usize)];
+    while let Some((offset, depth)) = pending.pop() {
+        match depths[offset] {
+            Some(seen) if seen == depth => continue,
+            Some(seen) => return Err(TranslateError::UnbalancedStack { offset, depths: (seen, depth) }),
+            None => depths[offset] = Some(depth),
+        }
+        let op = OpCode::from_byte(chunk.code[offset]).expect("the chunk was verified");
+        let effect = op.stack_effect();
+        let depth = depth - effect.pops + effect.pushes;
+        let next = offset + 1 + op.operands().width();
+        let target = || jump_target(chunk, offset, op) as usize;
+        match op {
+            OpCode::OpReturn => {}
+            OpCode::OpJump | OpCode::OpLoop => pending.push((target(), depth)),
+            OpCode::OpJumpIfFalse => pending.extend([(target(), depth), (next, depth)]),
+            _ => pending.push((next, depth)),
+        }
+    }
+    Ok(depths)
+}
+
+// runs register code with the same limits, arithmetic and results as
+// VirtualMachine::interpret, so the two engines can be compared directly.
+// fuel and interrupts bound a run as on the stack machine, but a stopped
+// run cannot be resumed; the next run starts over
+pub struct RegisterMachine<V = Value> {
+    registers: Vec<V>,
+    live: usize, //registers that hold what the stack machine's stack would
+    config: VmConfig,
+    error: Option<RuntimeError<V>>,
+    output: Option<Box<dyn Write>>, //where print goes, stdout when unset
+    fuel: Option<u64>,              //register instructions left, None for unlimited
+    interrupt: Arc<AtomicBool>,     //set by an InterruptHandle, checked at backward jumps
+}
+
+impl<V: Number> Default for RegisterMachine<V> {
+    fn default() -> Self {
+        RegisterMachine::new()
+    }
+}
+
+impl<V: Number> RegisterMachine<V> {
+    pub fn new() -> Self {
+        RegisterMachine {
+            registers: Vec::new(),
+            live: 0,
+            config: VmConfig::default(),
+            error: None,
+            output: None,
+            fuel: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: VmConfig) {
+        self.config = config;
+    }
+
+    //send printed values to sink instead of stdout
+    pub fn set_output(&mut self, sink: Box<dyn Write>) {
+        self.output = Some(sink);
+    }
+
+    //go back to stdout and hand back the sink
+    pub fn take_output(&mut self) -> Option<Box<dyn Write>> {
+        self.output.take()
+    }
+
+    //limit each run to about fuel register instructions; None removes the
+    //limit. the budget is checked at backward jumps, so a run can overshoot
+    //it by one pass of a loop body, and fused instructions count once, so it
+    //goes further than on the stack machine
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    //a handle other threads can use to stop a run at its next backward jump;
+    //a request no jump has seen is dropped when the next run starts
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle { flag: Arc::clone(&self.interrupt) }
+    }
+
+    //the registers standing for the stack, bottom first, after the last run
+    pub fn stack(&self) -> &[V] {
+        &self.registers[..self.live]
+    }
+
+    //details of the most recent InterpretRuntimeError
+    pub fn runtime_error(&self) -> Option<&RuntimeError<V>> {
+        self.error.as_ref()
+    }
+
+    //translates and runs chunk; one that has no register form is an
+    //InterpretCompileError
+    pub fn interpret(&mut self, chunk: Chunk<V>) -> InterpretResult {
+        self.error = None;
+        self.live = 0;
+        match translate(&chunk) {
+            Ok(program) => self.run(&program),
+            Err(_) => InterpretResult::InterpretCompileError,
+        }
+    }
+
+    pub fn run(&mut self, program: &RegisterChunk<V>) -> InterpretResult {
+        self.error = None;
+        self.interrupt.store(false, Ordering::Relaxed);
+        self.registers.clear();
+        self.registers.resize(program.registers, V::ZERO);
+        let (mut at, mut start) = (0, 0);
+        // an unlimited run counts down from u64::MAX, which never reaches zero
+        let mut fuel = self.fuel.unwrap_or(u64::MAX);
+        let result = self.execute(program, &mut at, &mut start, &mut fuel);
+        // the straight run the last instruction ended is still uncounted
+        fuel = fuel.saturating_sub((at + 1 - start) as u64);
+        if let Some(left) = self.fuel.as_mut() {
+            *left = fuel;
+        }
+        let site = program.sites[at];
+        self.live = site.live;
+        match result {
+            Ok(result) => result,
+            Err(kind) => {
+                let result = match kind {
+                    ErrorKind::Interrupted => InterpretResult::InterpretInterrupted,
+                    _ => InterpretResult::InterpretRuntimeError,
+                };
+                self.error = Some(RuntimeError { kind, offset: site.offset, line: Some(site.line) });
+                result
+            }
+        }
+    }
+
+    // runs until Return, an error or the fuel runs out; at is left on the
+    // instruction that stopped. fuel is charged for a straight run of
+    // instructions, from start, when a jump ends it, and only checked at
+    // backward jumps, so the hot path pays nothing for it; a run can go past
+    // its budget by at most one pass of a loop body
+    fn execute(
+        &mut self,
+        program: &RegisterChunk<V>,
+        at: &mut usize,
+        start: &mut usize,
+        fuel: &mut u64,
+    ) -> Result<InterpretResult, ErrorKind<V>> {
+        let RegisterMachine { registers, config, output, interrupt, .. } = self;
+        let (code, values) = (&program.code[..], &program.values[..]);
+        let mode = config.arithmetic;
+        // a push into register dst finds dst values already on the stack
+        let push = |dst: usize| if dst < config.max_stack { Ok(()) } else { Err(ErrorKind::StackOverflow) };
+        // heap bytes once value is pushed, heap counting what the stack already
+        // holds; the stack machine's VirtualMachine::heap_bytes at that point
+        let charge = |heap: usize, value: &V| {
+            let heap = heap + value.heap_size();
+            if heap > config.max_heap_bytes { Err(ErrorKind::OutOfMemory) } else { Ok(heap) }
+        };
+
+        let mut heap = 0;
+        let mut pc = 0;
+        loop {
+            *at = pc;
+            pc += 1;
+            match code[*at] {
+                Instruction::Return => return Ok(InterpretResult::InterpretSuccess),
+                Instruction::Load { dst, constant } => {
+                    push(dst)?;
+                    heap = charge(heap, &values[constant])?;
+                    registers[dst] = values[constant].clone();
+                }
+                Instruction::Copy { dst, src } => {
+                    push(dst)?;
+                    heap = charge(heap, &registers[src])?;
+                    registers[dst] = registers[src].clone();
+                }
+                Instruction::Move { dst, src } => registers[dst] = registers[src].clone(),
+                Instruction::Unary { op, dst, src } => {
+                    let value = &registers[src];
+                    let result = match op {
+                        OpCode::OpNegate => value.neg(mode),
+                        _ => value.bit_not(),
+                    };
+                    let result = result.map_err(|e| VirtualMachine::arithmetic_error(op, None, value.clone(), e))?;
+                    heap = charge(heap - value.heap_size(), &result)?;
+                    registers[dst] = result;
+                }
+                Instruction::Binary { op, dst, left, right } => {
+                    let (left, right) = (&registers[left], &registers[right]);
+                    let result = binary(op, left, right, mode)?;
+                    heap = charge(heap - left.heap_size() - right.heap_size(), &result)?;
+                    registers[dst] = result;
+                }
+                Instruction::BinaryConstant { op, dst, left, constant } => {
+                    let left = &registers[left];
+                    let result = binary(op, left, &values[constant], mode)?;
+                    heap = charge(heap - left.heap_size(), &result)?;
+                    registers[dst] = result;
+                }
+                Instruction::Jump { target } => {
+                    *fuel = fuel.saturating_sub((pc - *start) as u64);
+                    // a backward jump is the only way to run forever, so it
+                    // is where fuel and interrupts are checked, as the stack
+                    // machine checks interrupts at OpLoop
+                    if target < pc {
+                        if *fuel == 0 {
+                            *start = pc;
+                            return Ok(InterpretResult::InterpretOutOfFuel);
+                        }
+                        if interrupt.load(Ordering::Relaxed) && interrupt.swap(false, Ordering::Relaxed) {
+                            *start = pc;
+                            return Err(ErrorKind::Interrupted);
+                        }
+                    }
+                    *start = target;
+                    pc = target;
+                }
+                Instruction::JumpIfFalse { cond, target } => {
+                    heap -= registers[cond].heap_size();
+                    // zero is the only falsey value
+                    if registers[cond].is_zero() {
+                        *fuel = fuel.saturating_sub((pc - *start) as u64);
+                        *start = target;
+                        pc = target;
+                    }
+                }
+                Instruction::Pop { src } => heap -= registers[src].heap_size(),
+                Instruction::Print { src } => {
+                    let value = &registers[src];
+                    heap -= value.heap_size();
+                    let written = match output.as_mut() {
+                        Some(sink) => writeln!(sink, "{value}"),
+                        None => writeln!(io::stdout(), "{value}"),
+                    };
+                    written.map_err(|e| ErrorKind::OutputFailed(e.kind()))?;
+                }
+            }
+        }
+    }
+}
+
+fn binary<V: Number>(op: OpCode, left: &V, right: &V, mode: Arithmetic) -> Result<V, ErrorKind<V>> {
+    let result = match op {
+        OpCode::OpAdd => left.add(right, mode),
+        OpCode::OpSubtract => left.sub(right, mode),
+        OpCode::OpMultiply => left.mul(right, mode),
+        OpCode::OpDivide => left.div(right, mode),
+        OpCode::OpModulo => left.rem(right, mode),
+        OpCode::OpPower => left.pow(right, mode),
+        OpCode::OpBitAnd => left.bit_and(right),
+        OpCode::OpBitOr => left.bit_or(right),
+        OpCode::OpBitXor => left.bit_xor(right),
+        OpCode::OpShl => left.shl(right, mode),
+        _ => left.shr(right),
+    };
+    result.map_err(|e| VirtualMachine::arithmetic_error(op, Some(left.clone()), right.clone(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChunkBuilder;
+
+    #[test]
+    fn translates_stack_slots_to_registers() {
+        // 1 2 3 rot swap pop -> 2 1 - print, with a jump over dead code
+        let mut b = ChunkBuilder::<Value>::default();
+        for v in 1..=3 {
+            b.emit_constant(Value::Int(v));
+        }
+        b.emit_op(OpCode::OpRot).emit_op(OpCode::OpSwap).emit_op(OpCode::OpPop);
+        let skip = b.emit_jump();
+        b.emit_op(OpCode::OpNegate);
+        b.patch(skip);
+        b.set_line(2).emit_op(OpCode::OpSubtract).emit_op(OpCode::OpPrint);
+        b.emit_constant(Value::Int(4)).emit_with_constant(OpCode::OpAddConstant, Value::Int(1));
+        b.emit_op(OpCode::OpReturn);
+        let program = b.finish().unwrap().to_registers().unwrap();
+
+        let listing: Vec<String> = program.code().iter().map(Instruction::to_string).collect();
+        assert_eq!(
+            listing,
+            [
+                "r0 = k0", "r1 = k1", "r2 = k2",
+                "r4 = r0", "r0 = r1", "r1 = r2", "r2 = r4",
+                "r4 = r1", "r1 = r2", "r2 = r4",
+                "pop r2",
+                "jump 0012",
+                "r0 = OpSubtract r0, r1",
+                "print r0",
+                "r0 = k3",
+                "r0 = OpAdd r0, k4",
+                "return",
+            ]
+        );
+        assert_eq!(program.registers(), 5);
+
+        let mut vm = RegisterMachine::new();
+        vm.set_output(Box::new(io::sink()));
+        assert_eq!(vm.run(&program), InterpretResult::InterpretSuccess);
+        assert_eq!(vm.stack(), &[Value::Int(5)]);
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_translate() {
+        // the loop pushes a value each time round, so its head has no fixed depth
+        let mut b = ChunkBuilder::<Value>::default();
+        let top = b.loop_label();
+        b.emit_constant(Value::Int(1)).emit_loop(top);
+        let chunk = b.finish().unwrap();
+        let error = chunk.to_registers().unwrap_err();
+        assert_eq!(error, TranslateError::UnbalancedStack { offset: 0, depths: (0, 1) });
+        assert_eq!(error.to_string(), "0000: reached with 0 and with 1 values on the stack");
+        assert_eq!(RegisterMachine::new().interpret(chunk), InterpretResult::InterpretCompileError);
+
+        let mut c = Chunk::init_chunk();
+        c.write_to_chunk(OpCode::OpAdd.byte(), 1);
+        assert!(matches!(translate(&c), Err(TranslateError::Invalid(_))));
+    }
+
+    #[test]
+    fn fuel_and_interrupts_bound_a_run() {
+        // counts up forever; a load, an add and the jump each time round
+        let mut b = ChunkBuilder::<Value>::default();
+        b.emit_constant(Value::Int(0));
+        let top = b.loop_label();
+        b.emit_constant(Value::Int(1)).emit_op(OpCode::OpAdd).emit_loop(top);
+        let program = b.finish().unwrap().to_registers().unwrap();
+
+        let mut vm = RegisterMachine::new();
+        vm.set_fuel(Some(7));
+        assert_eq!(vm.run(&program), InterpretResult::InterpretOutOfFuel);
+        assert_eq!(vm.fuel(), Some(0));
+        assert_eq!(vm.stack(), &[Value::Int(2)]);
+        assert!(vm.runtime_error().is_none());
+
+        // interrupt until the loop has seen it; a run only stops that way
+        vm.set_fuel(None);
+        let handle = vm.interrupt_handle();
+        let done = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let done = Arc::clone(&done);
+            std::thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    handle.interrupt();
+                    std::thread::yield_now();
+                }
+            })
+        };
+        assert_eq!(vm.run(&program), InterpretResult::InterpretInterrupted);
+        done.store(true, Ordering::Relaxed);
+        worker.join().unwrap();
+        let error = vm.runtime_error().unwrap();
+        assert_eq!((error.kind.clone(), error.line), (ErrorKind::Interrupted, Some(1)));
+    }
+}
@@ -10,14 +10,17 @@ pub enum TokenType {
     TokenDot,
     TokenSemicolon,
     TokenMinus, TokenPlus,
-    TokenSlash, TokenStar,
+    TokenSlash, TokenStar, TokenStarStar,
+    TokenPercent,
+    TokenAmpersand, TokenPipe, TokenCaret, TokenTilde,
+    TokenShiftLeft, TokenShiftRight,
     TokenNot, TokenNotEqual,
     TokenEqual, TokenEqualEqual,
     TokenLess, TokenLessEqual,
     TokenGreater, TokenGreaterEqual,
     TokenIdentifier,
     TokenString,
-    TokenNumber,
+    TokenInteger, TokenFloat,
     TokenTrue, TokenFalse,
     TokenAnd, TokenOr,
     TokenIf, TokenElse,
@@ -146,13 +149,15 @@ impl Scanner {
         self.make_token(TokenType::TokenString)
     }
 
+    // `42` is an integer, `42.0` a float; a trailing `.` is not part of the number
     fn get_literal_number(&mut self) -> Token {
         while is_digit(self.peek()) { self.advance(); }
         if self.peek() == b'.' && is_digit(self.peek_next()) {
             self.advance(); // '.'
             while is_digit(self.peek()) { self.advance(); }
+            return self.make_token(TokenType::TokenFloat);
         }
-        self.make_token(TokenType::TokenNumber)
+        self.make_token(TokenType::TokenInteger)
     }
 
     fn get_identifier(&mut self) -> Token {
@@ -206,8 +211,16 @@ impl Scanner {
             b';' => self.make_token(TokenType::TokenSemicolon),
             b'-' => self.make_token(TokenType::TokenMinus),
             b'+' => self.make_token(TokenType::TokenPlus),
-            b'*' => self.make_token(TokenType::TokenStar),
+            b'*' => {
+                if self.match_next(b'*') { self.make_token(TokenType::TokenStarStar) }
+                else { self.make_token(TokenType::TokenStar) }
+            }
             b'/' => self.make_token(TokenType::TokenSlash),
+            b'%' => self.make_token(TokenType::TokenPercent),
+            b'&' => self.make_token(TokenType::TokenAmpersand),
+            b'|' => self.make_token(TokenType::TokenPipe),
+            b'^' => self.make_token(TokenType::TokenCaret),
+            b'~' => self.make_token(TokenType::TokenTilde),
 
             b'!' => {
                 if self.match_next(b'=') { self.make_token(TokenType::TokenNotEqual) }
@@ -219,10 +232,12 @@ impl Scanner {
             }
             b'<' => {
                 if self.match_next(b'=') { self.make_token(TokenType::TokenLessEqual) }
+                else if self.match_next(b'<') { self.make_token(TokenType::TokenShiftLeft) }
                 else { self.make_token(TokenType::TokenLess) }
             }
             b'>' => {
                 if self.match_next(b'=') { self.make_token(TokenType::TokenGreaterEqual) }
+                else if self.match_next(b'>') { self.make_token(TokenType::TokenShiftRight) }
                 else { self.make_token(TokenType::TokenGreater) }
             }
 
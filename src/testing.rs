@@ -0,0 +1,35 @@
+// fixtures shared by the unit tests; only compiled for cfg(test)
+
+use std::fmt;
+
+use crate::{Chunk, ChunkBuilder, Number, OpCode};
+
+// counts n down to zero by step, printing each value on the way. every pass
+// shuffles the stack with both values on it, subtracts the step as
+// OpConstant + OpSubtract, negates twice and returns to the top through a
+// jump-to-jump chain; dead code follows the OpReturn
+pub(crate) fn countdown<V: Number>(n: V, step: V) -> Chunk<V> {
+    let mut b = ChunkBuilder::<V>::default();
+    b.emit_constant(n);
+    let top = b.loop_label();
+    b.set_line(2).emit_op(OpCode::OpDup).emit_op(OpCode::OpPrint).emit_op(OpCode::OpDup);
+    let done = b.emit_jump_if_false();
+    // n step -> step n -> step n step -> n step step -> n
+    b.set_line(3).emit_constant(step.clone()).emit_op(OpCode::OpSwap).emit_op(OpCode::OpOver);
+    b.emit_op(OpCode::OpRot).emit_op(OpCode::OpPop).emit_op(OpCode::OpPop);
+    b.emit_constant(step.clone()).emit_op(OpCode::OpSubtract);
+    b.emit_op(OpCode::OpNegate).emit_op(OpCode::OpNegate);
+    let back = b.emit_jump();
+    b.patch(done);
+    b.set_line(4).emit_op(OpCode::OpReturn);
+    b.emit_constant(step).emit_op(OpCode::OpPrint).emit_op(OpCode::OpReturn);
+    b.patch(back);
+    b.emit_loop(top);
+    b.finish().unwrap()
+}
+
+// assert_eq! on the Debug text, so NaN matches NaN wherever it is nested
+#[track_caller]
+pub(crate) fn assert_same<T: fmt::Debug>(left: &T, right: &T, context: impl fmt::Display) {
+    assert_eq!(format!("{left:?}"), format!("{right:?}"), "{context}");
+}
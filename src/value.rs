@@ -0,0 +1,401 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::{Arithmetic, BigInt, Number, NumberError};
+
+// the number type of the language: exact integers and doubles.
+// int op int stays an int; a float on either side makes both floats.
+// integers are i64 until an operation overflows, then they move to a heap
+// bigint, and a bigint result that fits in an i64 moves back, so Big only
+// ever holds values outside the i64 range. the arithmetic mode only applies
+// to floats: integers never wrap, saturate or overflow.
+// bitwise operations need integers and treat negative values as two's
+// complement with unlimited sign bits, so a << n is a * 2**n and a >> n is
+// floor(a / 2**n) for any n.
+// integer division truncates toward zero and the remainder takes the sign
+// of the dividend, so -7 / 2 is -3 and -7 % 2 is -1
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Big(Rc<BigInt>),
+}
+
+impl Value {
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Value::Int(i) => *i as f64,
+            Value::Float(f) => *f,
+            Value::Big(b) => b.to_f64(),
+        }
+    }
+
+    pub fn is_int(&self) -> bool {
+        matches!(self, Value::Int(_) | Value::Big(_))
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self, Value::Float(_))
+    }
+
+    pub fn is_big(&self) -> bool {
+        matches!(self, Value::Big(_))
+    }
+
+    fn is_negative(&self) -> bool {
+        match self {
+            Value::Int(i) => *i < 0,
+            Value::Float(f) => *f < 0.0,
+            Value::Big(b) => b.is_negative(),
+        }
+    }
+
+    // both operands as bigints, unless either is a float
+    fn integers(&self, rhs: &Value) -> Result<(BigInt, BigInt), NumberError> {
+        if self.is_float() || rhs.is_float() {
+            return Err(NumberError::NotAnInteger);
+        }
+        Ok((self.to_big(), rhs.to_big()))
+    }
+
+    fn to_big(&self) -> BigInt {
+        match self {
+            Value::Int(i) => BigInt::from(*i),
+            Value::Big(b) => BigInt::clone(b),
+            Value::Float(_) => unreachable!("floats are never promoted to bigints"),
+        }
+    }
+
+    // applies the i64, bigint or float form of an operation after promotion;
+    // the i64 form returns None on overflow (or a zero divisor) and the bigint
+    // form takes over
+    #[inline(always)]
+    fn promote(
+        &self,
+        rhs: &Value,
+        int: impl FnOnce(i64, i64) -> Option<i64>,
+        big: impl FnOnce(&BigInt, &BigInt) -> Result<BigInt, NumberError>,
+        float: impl FnOnce(f64, f64) -> Result<f64, NumberError>,
+    ) -> Result<Value, NumberError> {
+        match (self, rhs) {
+            (Value::Float(_), _) | (_, Value::Float(_)) => float(self.as_f64(), rhs.as_f64()).map(Value::Float),
+            (Value::Int(a), Value::Int(b)) => match int(*a, *b) {
+                Some(i) => Ok(Value::Int(i)),
+                None => big(&BigInt::from(*a), &BigInt::from(*b)).map(Value::from),
+            },
+            (a, b) => big(&a.to_big(), &b.to_big()).map(Value::from),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Value {
+        Value::Int(i)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Value {
+        Value::Float(f)
+    }
+}
+
+// demotes to Int when the value fits
+impl From<BigInt> for Value {
+    fn from(b: BigInt) -> Value {
+        match b.to_i64() {
+            Some(i) => Value::Int(i),
+            None => Value::Big(Rc::new(b)),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{i}"),
+            // keeps the decimal point, so 2.0 never reads as an int
+            Value::Float(x) => write!(f, "{x:?}"),
+            Value::Big(b) => write!(f, "{b}"),
+        }
+    }
+}
+
+// integer powers past this many bits are reported as an overflow rather
+// than spending unbounded time and memory on them
+const MAX_POWER_BITS: u64 = 1 << 16;
+
+const INT_TAG: u8 = 0;
+const FLOAT_TAG: u8 = 1;
+const BIG_TAG: u8 = 2;
+
+impl Number for Value {
+    const ZERO: Self = Value::Int(0);
+
+    fn is_zero(&self) -> bool {
+        match self {
+            Value::Int(i) => *i == 0,
+            Value::Float(f) => *f == 0.0,
+            Value::Big(b) => b.is_zero(),
+        }
+    }
+
+    // the Rc allocation, its two counts and the BigInt, then the limbs. every
+    // value on the stack counts in full, so one duplicated with OpDup counts twice
+    fn heap_size(&self) -> usize {
+        match self {
+            Value::Big(b) => 2 * std::mem::size_of::<usize>() + std::mem::size_of::<BigInt>() + b.heap_size(),
+            _ => 0,
+        }
+    }
+
+    fn add(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+        self.promote(rhs, i64::checked_add, |a, b| Ok(a.add(b)), |a, b| a.add(&b, mode))
+    }
+
+    fn sub(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+        self.promote(rhs, i64::checked_sub, |a, b| Ok(a.sub(b)), |a, b| a.sub(&b, mode))
+    }
+
+    fn mul(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+        self.promote(rhs, i64::checked_mul, |a, b| Ok(a.mul(b)), |a, b| a.mul(&b, mode))
+    }
+
+    fn div(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+        self.promote(
+            rhs,
+            i64::checked_div,
+            |a, b| a.divmod(b).map(|(q, _)| q).ok_or(NumberError::DivisionByZero),
+            |a, b| a.div(&b, mode),
+        )
+    }
+
+    fn rem(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+        self.promote(
+            rhs,
+            i64::checked_rem,
+            |a, b| a.divmod(b).map(|(_, r)| r).ok_or(NumberError::DivisionByZero),
+            |a, b| a.rem(&b, mode),
+        )
+    }
+
+    // integer powers follow the i64 rules for negative exponents: they
+    // truncate to zero unless the base is 1 or -1
+    fn pow(&self, rhs: &Self, mode: Arithmetic) -> Result<Self, NumberError> {
+        if self.is_float() || rhs.is_float() {
+            return self.as_f64().pow(&rhs.as_f64(), mode).map(Value::Float);
+        }
+        // bases whose powers never grow, for any exponent
+        match self {
+            Value::Int(0) if rhs.is_negative() => return Err(NumberError::DivisionByZero),
+            Value::Int(0 | 1) if rhs.is_zero() => return Ok(Value::Int(1)),
+            Value::Int(0 | 1) => return Ok(self.clone()),
+            Value::Int(-1) if rhs.rem(&Value::Int(2), mode)?.is_zero() => return Ok(Value::Int(1)),
+            Value::Int(-1) => return Ok(self.clone()),
+            _ => {}
+        }
+        let exp = match rhs {
+            _ if rhs.is_negative() => return Ok(Value::Int(0)),
+            Value::Int(e) if self.to_big().bits().saturating_mul(*e as u64) <= MAX_POWER_BITS => *e as u32,
+            _ => return Err(NumberError::Overflow),
+        };
+        if let Value::Int(base) = self
+            && let Some(i) = base.checked_pow(exp)
+        {
+            return Ok(Value::Int(i));
+        }
+        Ok(Value::from(self.to_big().pow(exp)))
+    }
+
+    fn neg(&self, mode: Arithmetic) -> Result<Self, NumberError> {
+        match self {
+            Value::Int(i) => Ok(i.checked_neg().map_or_else(|| Value::from(BigInt::from(*i).neg()), Value::Int)),
+            Value::Float(f) => f.neg(mode).map(Value::Float),
+            Value::Big(b) => Ok(Value::from(b.neg())),
+        }
+    }
+
+    fn bit_and(&self, rhs: &Self) -> Result<Self, NumberError> {
+        match (self, rhs) {
+            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a & b)),
+            _ => self.integers(rhs).map(|(a, b)| Value::from(a.bit_and(&b))),
+        }
+    }
+
+    fn bit_or(&self, rhs: &Self) -> Result<Self, NumberError> {
+        match (self, rhs) {
+            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a | b)),
+            _ => self.integers(rhs).map(|(a, b)| Value::from(a.bit_or(&b))),
+        }
+    }
+
+    fn bit_xor(&self, rhs: &Self) -> Result<Self, NumberError> {
+        match (self, rhs) {
+            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a ^ b)),
+            _ => self.integers(rhs).map(|(a, b)| Value::from(a.bit_xor(&b))),
+        }
+    }
+
+    fn bit_not(&self) -> Result<Self, NumberError> {
+        match self {
+            Value::Int(i) => Ok(Value::Int(!i)),
+            Value::Float(_) => Err(NumberError::NotAnInteger),
+            Value::Big(b) => Ok(Value::from(b.bit_not())),
+        }
+    }
+
+    fn shl(&self, rhs: &Self, _mode: Arithmetic) -> Result<Self, NumberError> {
+        let (a, _) = self.integers(rhs)?;
+        if rhs.is_negative() {
+            return Err(NumberError::NegativeShift);
+        }
+        if let (Value::Int(a), Value::Int(n @ 0..64)) = (self, rhs)
+            && (a << n) >> n == *a
+        {
+            return Ok(Value::Int(a << n));
+        }
+        match rhs {
+            _ if a.is_zero() => Ok(Value::Int(0)),
+            Value::Int(n) if a.bits().saturating_add(*n as u64) <= MAX_POWER_BITS => {
+                Ok(Value::from(a.mul(&BigInt::from(2).pow(*n as u32))))
+            }
+            _ => Err(NumberError::Overflow),
+        }
+    }
+
+    fn shr(&self, rhs: &Self) -> Result<Self, NumberError> {
+        let (a, _) = self.integers(rhs)?;
+        if rhs.is_negative() {
+            return Err(NumberError::NegativeShift);
+        }
+        match (self, rhs) {
+            (Value::Int(a), Value::Int(n)) => Ok(Value::Int(a >> (*n).min(63))),
+            // every bit shifted out leaves only the sign
+            (_, Value::Int(n)) if (*n as u64) < a.bits() => {
+                let (q, r) = a.divmod(&BigInt::from(2).pow(*n as u32)).expect("a power of two is not zero");
+                // divmod truncates toward zero; a >> n rounds toward -infinity
+                let floor = if r.is_negative() { q.sub(&BigInt::from(1)) } else { q };
+                Ok(Value::from(floor))
+            }
+            _ => Ok(Value::Int(if a.is_negative() { -1 } else { 0 })),
+        }
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(i) => {
+                out.push(INT_TAG);
+                i.write_bytes(out);
+            }
+            Value::Float(f) => {
+                out.push(FLOAT_TAG);
+                f.write_bytes(out);
+            }
+            Value::Big(b) => {
+                out.push(BIG_TAG);
+                b.write_bytes(out);
+            }
+        }
+    }
+
+    fn read_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        let (&tag, rest) = bytes.split_first()?;
+        let (value, width) = match tag {
+            INT_TAG => i64::read_bytes(rest).map(|(i, n)| (Value::Int(i), n))?,
+            FLOAT_TAG => f64::read_bytes(rest).map(|(f, n)| (Value::Float(f), n))?,
+            BIG_TAG => BigInt::read_bytes(rest).map(|(b, n)| (Value::from(b), n))?,
+            _ => return None,
+        };
+        Some((value, 1 + width))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Arithmetic::Wrapping;
+
+    #[test]
+    fn promotion_rules() {
+        let int = Value::Int;
+        let float = Value::Float;
+        assert_eq!(int(7).add(&int(2), Wrapping), Ok(int(9)));
+        assert_eq!(int(7).div(&int(2), Wrapping), Ok(int(3)));
+        assert_eq!(int(7).div(&float(2.0), Wrapping), Ok(float(3.5)));
+        assert_eq!(float(0.5).mul(&int(4), Wrapping), Ok(float(2.0)));
+        assert_eq!(int(-7).div(&int(2), Wrapping), Ok(int(-3)));
+        assert_eq!(int(-7).rem(&int(2), Wrapping), Ok(int(-1)));
+        assert_eq!(int(7).rem(&int(-2), Wrapping), Ok(int(1)));
+        assert_eq!(float(-7.5).rem(&int(2), Wrapping), Ok(float(-1.5)));
+        assert_eq!(int(1).div(&int(0), Wrapping), Err(NumberError::DivisionByZero));
+        assert_eq!(int(1).div(&float(0.0), Wrapping), Ok(float(f64::INFINITY)));
+        // exact past 2^53, where a double would round
+        assert_eq!(int(1 << 53).add(&int(1), Wrapping), Ok(int((1 << 53) + 1)));
+    }
+
+    #[test]
+    fn overflow_promotes_to_bigint_and_back() {
+        let max = Value::Int(i64::MAX);
+        let one = Value::Int(1);
+        let past = max.add(&one, Arithmetic::Checked).unwrap();
+        assert!(past.is_big());
+        assert_eq!(past.to_string(), "9223372036854775808");
+        assert_eq!(past.sub(&one, Wrapping), Ok(max.clone()));
+
+        let square = max.mul(&max, Wrapping).unwrap();
+        assert_eq!(square.to_string(), "85070591730234615847396907784232501249");
+        assert_eq!(square.div(&max, Wrapping), Ok(max.clone()));
+        assert_eq!(square.rem(&Value::Int(10), Wrapping), Ok(Value::Int(9)));
+        assert_eq!(square.div(&Value::Int(0), Wrapping), Err(NumberError::DivisionByZero));
+
+        let min = Value::Int(i64::MIN);
+        assert_eq!(min.neg(Wrapping).unwrap().to_string(), "9223372036854775808");
+        assert_eq!(min.div(&Value::Int(-1), Wrapping).unwrap().to_string(), "9223372036854775808");
+        assert_eq!(min.rem(&Value::Int(-1), Wrapping), Ok(Value::Int(0)));
+        assert_eq!(past.mul(&Value::Float(0.5), Wrapping), Ok(Value::Float(4611686018427387904.0)));
+
+        let two = Value::Int(2);
+        assert_eq!(two.pow(&Value::Int(62), Wrapping), Ok(Value::Int(1 << 62)));
+        assert_eq!(two.pow(&Value::Int(64), Wrapping).unwrap().to_string(), "18446744073709551616");
+        assert_eq!(two.pow(&Value::Int(-1), Wrapping), Ok(Value::Int(0)));
+        assert_eq!(two.pow(&Value::Float(-1.0), Wrapping), Ok(Value::Float(0.5)));
+        assert_eq!(Value::Int(-1).pow(&past, Wrapping), Ok(Value::Int(1)));
+        assert_eq!(two.pow(&Value::Int(1 << 20), Wrapping), Err(NumberError::Overflow));
+    }
+
+    #[test]
+    fn bitwise_operations_on_every_width() {
+        let big = |text: &str| Value::from(text.parse::<BigInt>().unwrap());
+        let int = Value::Int;
+        assert_eq!(int(0b1100).bit_xor(&int(0b1010)), Ok(int(0b0110)));
+        assert_eq!(int(5).bit_not(), Ok(int(-6)));
+        assert_eq!(int(-7).shr(&int(1)), Ok(int(-4)));
+        assert_eq!(int(-7).shr(&int(1000)), Ok(int(-1)));
+        assert_eq!(int(1).shl(&int(62), Wrapping), Ok(int(1 << 62)));
+        assert_eq!(int(1).shl(&int(64), Wrapping), Ok(big("18446744073709551616")));
+        assert_eq!(int(5).shl(&int(100), Wrapping), Ok(big("6338253001141147007483516026880")));
+        assert_eq!(int(-1).bit_and(&big("1208925819614629174706176")), Ok(big("1208925819614629174706176")));
+        assert_eq!(big("-1180591620717411303424").shr(&int(3)), Ok(big("-147573952589676412928")));
+        assert_eq!(big("-1180591620717411303425").shr(&int(3)), Ok(big("-147573952589676412929")));
+        assert_eq!(big("1180591620717411303424").shr(&int(80)), Ok(int(0)));
+        assert_eq!(big("-1180591620717411303424").shr(&int(80)), Ok(int(-1)));
+        assert_eq!(int(1).shl(&int(-1), Wrapping), Err(NumberError::NegativeShift));
+        assert_eq!(int(1).shl(&int(1 << 20), Wrapping), Err(NumberError::Overflow));
+        assert_eq!(int(0).shl(&int(1 << 20), Wrapping), Ok(int(0)));
+        assert_eq!(int(1).bit_or(&Value::Float(2.0)), Err(NumberError::NotAnInteger));
+        assert_eq!(Value::Float(2.0).shr(&int(1)), Err(NumberError::NotAnInteger));
+    }
+
+    #[test]
+    fn displays_and_encodes_every_kind() {
+        assert_eq!(Value::Int(42).to_string(), "42");
+        assert_eq!(Value::Float(42.0).to_string(), "42.0");
+        assert!(Value::Float(-0.0).is_zero());
+        let big = Value::from("-123456789012345678901234567890".parse::<BigInt>().unwrap());
+        for value in [Value::Int(-3), Value::Float(2.5), big] {
+            let mut bytes = Vec::new();
+            value.write_bytes(&mut bytes);
+            assert_eq!(Value::read_bytes(&bytes), Some((value, bytes.len())));
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::fmt;
+
+use crate::{Chunk, Number, OpCode, Operands, u8_to_opcode};
+
+// structural problems that would make the VM misbehave on a chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    Empty,
+    LineTableMismatch { code: usize, lines: usize },
+    UnknownOpcode { offset: usize, byte: u8 },
+    TruncatedOperand { offset: usize, op: OpCode },
+    BadConstant { offset: usize, index: u8 },
+    BadJumpTarget { offset: usize, target: isize },
+    FallsOffEnd { offset: usize },
+    // some path reaches op with fewer than the values it pops
+    StackUnderflow { offset: usize, op: OpCode, depth: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Empty => write!(f, "chunk has no instructions"),
+            VerifyError::LineTableMismatch { code, lines } => {
+                write!(f, "line table has {lines} entries for {code} bytes of code")
+            }
+            VerifyError::UnknownOpcode { offset, byte } => {
+                write!(f, "{offset:04}: unknown opcode 0x{byte:02X}")
+            }
+            VerifyError::TruncatedOperand { offset, op } => {
+                write!(f, "{offset:04}: {op:?} is missing its operand")
+            }
+            VerifyError::BadConstant { offset, index } => {
+                write!(f, "{offset:04}: constant index {index} is out of range")
+            }
+            VerifyError::BadJumpTarget { offset, target } => {
+                write!(f, "{offset:04}: jump target {target} is not an instruction")
+            }
+            VerifyError::FallsOffEnd { offset } => {
+                write!(f, "{offset:04}: execution can run past the end of the chunk")
+            }
+            VerifyError::StackUnderflow { offset, op, depth } => {
+                let needs = op.stack_effect().pops;
+                write!(f, "{offset:04}: {op} pops {needs} values but the stack can hold {depth}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl<V: Number> Chunk<V> {
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        verify(self)
+    }
+}
+
+// checks that every instruction decodes, every operand is in range,
+// every jump lands on the start of an instruction and no instruction can
+// pop from a stack that is too shallow
+pub fn verify<V: Number>(chunk: &Chunk<V>) -> Result<(), VerifyError> {
+    if chunk.code.is_empty() {
+        return Err(VerifyError::Empty);
+    }
+    if chunk.code.len() != chunk.lines.len() {
+        return Err(VerifyError::LineTableMismatch {
+            code: chunk.code.len(),
+            lines: chunk.lines.len(),
+        });
+    }
+
+    // first pass: decode and remember where instructions start
+    let mut starts = vec![false; chunk.code.len()];
+    let mut jumps = Vec::new();
+    let mut last = 0usize;
+    let mut offset = 0usize;
+    while offset < chunk.code.len() {
+        let byte = chunk.code[offset];
+        let op = u8_to_opcode(byte).ok_or(VerifyError::UnknownOpcode { offset, byte })?;
+        let width = op.operands().width();
+        if offset + width >= chunk.code.len() {
+            return Err(VerifyError::TruncatedOperand { offset, op });
+        }
+
+        match op.operands() {
+            Operands::None => {}
+            Operands::Constant => {
+                let index = chunk.code[offset + 1];
+                if index as usize >= chunk.values.len() {
+                    return Err(VerifyError::BadConstant { offset, index });
+                }
+            }
+            Operands::Jump | Operands::Loop => jumps.push((offset, jump_target(chunk, offset, op))),
+        }
+
+        starts[offset] = true;
+        last = offset;
+        offset += 1 + width;
+    }
+
+    // second pass: jumps must land on an instruction boundary
+    for (offset, target) in jumps {
+        let lands = usize::try_from(target)
+            .ok()
+            .and_then(|t| starts.get(t).copied())
+            .unwrap_or(false);
+        if !lands {
+            return Err(VerifyError::BadJumpTarget { offset, target });
+        }
+    }
+
+    if !matches!(u8_to_opcode(chunk.code[last]), Some(OpCode::OpReturn | OpCode::OpJump | OpCode::OpLoop)) {
+        return Err(VerifyError::FallsOffEnd { offset: last });
+    }
+
+    // third pass: follow every path with the shallowest stack that reaches
+    // each instruction; a deeper visit can't underflow where this one didn't
+    let mut shallowest: Vec<Option<usize>> = vec![None; chunk.code.len()];
+    let mut pending = vec![(0usize, 0usize)];
+    while let Some((offset, depth)) = pending.pop() {
+        if shallowest[offset].is_some_and(|seen| seen <= depth) {
+            continue;
+        }
+        shallowest[offset] = Some(depth);
+        let op = u8_to_opcode(chunk.code[offset]).expect("decoded in the first pass");
+        let effect = op.stack_effect();
+        if depth < effect.pops {
+            return Err(VerifyError::StackUnderflow { offset, op, depth });
+        }
+        let depth = depth - effect.pops + effect.pushes;
+        let next = offset + 1 + op.operands().width();
+        let target = || jump_target(chunk, offset, op) as usize;
+        match op {
+            OpCode::OpReturn => {}
+            OpCode::OpJump | OpCode::OpLoop => pending.push((target(), depth)),
+            OpCode::OpJumpIfFalse => pending.extend([(target(), depth), (next, depth)]),
+            _ => pending.push((next, depth)),
+        }
+    }
+    Ok(())
+}
+
+// where the jump or loop at offset goes; its operand must be in bounds
+pub(crate) fn jump_target<V: Number>(chunk: &Chunk<V>, offset: usize, op: OpCode) -> isize {
+    let jump = ((chunk.code[offset + 1] as isize) << 8) | chunk.code[offset + 2] as isize;
+    let next = (offset + 3) as isize;
+    if op.operands() == Operands::Loop { next - jump } else { next + jump }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Value, opcode_to_u8};
+
+    #[test]
+    fn accepts_hand_written_chunk() {
+        let mut c = Chunk::init_chunk();
+        let i = c.add_constant(Value::Int(7));
+        c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), 1);
+        c.write_to_chunk(i, 1);
+        c.write_to_chunk(opcode_to_u8(OpCode::OpReturn), 1);
+        assert_eq!(c.verify(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_bad_operands() {
+        let mut c = Chunk::init_chunk();
+        c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), 1);
+        c.write_to_chunk(3, 1);
+        c.write_to_chunk(opcode_to_u8(OpCode::OpReturn), 1);
+        assert_eq!(c.verify(), Err(VerifyError::BadConstant { offset: 0, index: 3 }));
+
+        let mut c = Chunk::init_chunk();
+        c.write_to_chunk(opcode_to_u8(OpCode::OpReturn), 1);
+        c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), 1);
+        assert_eq!(
+            c.verify(),
+            Err(VerifyError::TruncatedOperand { offset: 1, op: OpCode::OpConstant })
+        );
+
+        let mut c = Chunk::init_chunk();
+        c.write_to_chunk(0xFF, 1);
+        assert_eq!(c.verify(), Err(VerifyError::UnknownOpcode { offset: 0, byte: 0xFF }));
+    }
+
+    #[test]
+    fn rejects_jumps_into_operands_and_falling_off_the_end() {
+        // OpJump +1 lands inside the following OpConstant's operand
+        let mut c = Chunk::init_chunk();
+        let i = c.add_constant(Value::Int(1));
+        for b in [opcode_to_u8(OpCode::OpJump), 0, 1, opcode_to_u8(OpCode::OpConstant), i] {
+            c.write_to_chunk(b, 1);
+        }
+        c.write_to_chunk(opcode_to_u8(OpCode::OpReturn), 1);
+        assert_eq!(c.verify(), Err(VerifyError::BadJumpTarget { offset: 0, target: 4 }));
+
+        let mut c = Chunk::init_chunk();
+        let i = c.add_constant(Value::Int(1));
+        c.write_to_chunk(opcode_to_u8(OpCode::OpConstant), 1);
+        c.write_to_chunk(i, 1);
+        assert_eq!(c.verify(), Err(VerifyError::FallsOffEnd { offset: 0 }));
+    }
+
+    #[test]
+    fn rejects_stack_underflow_on_any_path() {
+        let chunk = |ops: &[OpCode]| {
+            let mut c = Chunk::init_chunk();
+            let i = c.add_constant(Value::Int(1));
+            for &op in ops {
+                c.write_to_chunk(opcode_to_u8(op), 1);
+                if op == OpCode::OpConstant {
+                    c.write_to_chunk(i, 1);
+                }
+            }
+            c
+        };
+        use OpCode::*;
+        assert_eq!(chunk(&[OpConstant, OpDup, OpOver, OpRot, OpSwap, OpPop, OpReturn]).verify(), Ok(()));
+
+        let error = chunk(&[OpConstant, OpSwap, OpReturn]).verify().unwrap_err();
+        assert_eq!(error, VerifyError::StackUnderflow { offset: 2, op: OpSwap, depth: 1 });
+        assert_eq!(error.to_string(), "0002: OpSwap pops 2 values but the stack can hold 1");
+
+        // taking the OpJumpIfFalse skips an OpConstant, so the second OpPop
+        // underflows on that path even though the fallthrough path is fine
+        let mut c = chunk(&[OpConstant, OpConstant, OpJumpIfFalse]);
+        for b in [0, 2, opcode_to_u8(OpConstant), 0, opcode_to_u8(OpPop), opcode_to_u8(OpPop), opcode_to_u8(OpReturn)] {
+            c.write_to_chunk(b, 1);
+        }
+        assert_eq!(c.verify(), Err(VerifyError::StackUnderflow { offset: 10, op: OpPop, depth: 0 }));
+    }
+}
@@ -0,0 +1,61 @@
+// fixtures shared by the integration tests and the benches; each of them
+// includes this module and uses only part of it
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use rust_vm_project::{Chunk, ChunkBuilder, Number, OpCode};
+
+// counts n down to zero by step, printing each value on the way. every pass
+// shuffles the stack with both values on it, subtracts the step as
+// OpConstant + OpSubtract, negates twice and returns to the top through a
+// jump-to-jump chain; dead code follows the OpReturn
+pub fn countdown<V: Number>(n: V, step: V) -> Chunk<V> {
+    let mut b = ChunkBuilder::<V>::default();
+    b.emit_constant(n);
+    let top = b.loop_label();
+    b.set_line(2).emit_op(OpCode::OpDup).emit_op(OpCode::OpPrint).emit_op(OpCode::OpDup);
+    let done = b.emit_jump_if_false();
+    // n step -> step n -> step n step -> n step step -> n
+    b.set_line(3).emit_constant(step.clone()).emit_op(OpCode::OpSwap).emit_op(OpCode::OpOver);
+    b.emit_op(OpCode::OpRot).emit_op(OpCode::OpPop).emit_op(OpCode::OpPop);
+    b.emit_constant(step.clone()).emit_op(OpCode::OpSubtract);
+    b.emit_op(OpCode::OpNegate).emit_op(OpCode::OpNegate);
+    let back = b.emit_jump();
+    b.patch(done);
+    b.set_line(4).emit_op(OpCode::OpReturn);
+    b.emit_constant(step).emit_op(OpCode::OpPrint).emit_op(OpCode::OpReturn);
+    b.patch(back);
+    b.emit_loop(top);
+    b.finish().unwrap()
+}
+
+// assert_eq! on the Debug text, so NaN matches NaN wherever it is nested
+#[track_caller]
+pub fn assert_same<T: fmt::Debug>(left: &T, right: &T, context: impl fmt::Display) {
+    assert_eq!(format!("{left:?}"), format!("{right:?}"), "{context}");
+}
+
+// a print sink whose bytes can still be read after a VM has taken it
+#[derive(Clone, Default)]
+pub struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Output {
+    pub fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
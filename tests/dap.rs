@@ -0,0 +1,50 @@
+// drives the binary's --dap mode with scripted DAP messages on stdin
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+fn frame(body: &str) -> String {
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+#[test]
+fn dap_session_over_stdio() {
+    let path = std::env::temp_dir().join(format!("dap_bin_{}.lox", std::process::id()));
+    std::fs::write(&path, "1 +\n2 *\n3").unwrap();
+    let program = path.to_str().unwrap().replace('\\', "\\\\");
+
+    let script = [
+        frame(r#"{"seq":1,"type":"request","command":"initialize","arguments":{"adapterID":"lox"}}"#),
+        frame(&format!(
+            r#"{{"seq":2,"type":"request","command":"launch","arguments":{{"program":"{program}"}}}}"#
+        )),
+        frame(&format!(
+            r#"{{"seq":3,"type":"request","command":"setBreakpoints","arguments":{{"source":{{"path":"{program}"}},"breakpoints":[{{"line":3}}]}}}}"#
+        )),
+        frame(r#"{"seq":4,"type":"request","command":"configurationDone"}"#),
+        frame(r#"{"seq":5,"type":"request","command":"variables","arguments":{"variablesReference":1}}"#),
+        frame(r#"{"seq":6,"type":"request","command":"continue","arguments":{"threadId":1}}"#),
+        frame(r#"{"seq":7,"type":"request","command":"continue","arguments":{"threadId":1}}"#),
+        frame(r#"{"seq":8,"type":"request","command":"disconnect"}"#),
+    ]
+    .concat();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust_vm_project"))
+        .arg("--dap")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Content-Length: "), "stdout must only carry DAP frames");
+    assert!(stdout.contains(r#""event":"initialized""#));
+    assert!(stdout.contains(r#""reason":"breakpoint""#));
+    assert!(stdout.contains(r#""name":"[1]","value":"2""#));
+    assert!(stdout.contains(r#""exitCode":0"#));
+    assert!(stdout.contains(r#""event":"terminated""#));
+}
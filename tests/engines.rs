@@ -0,0 +1,113 @@
+// runs every program on the stack machine and on the register machine and
+// checks that both finish the same way: same result, same values left
+// behind, same runtime error at the same offset and line, same output
+
+mod common;
+
+use common::{Output, assert_same, countdown};
+use rust_vm_project::compiler::{CompileOptions, compile_with};
+use rust_vm_project::{
+    Arithmetic, Chunk, ChunkBuilder, InterpretResult, Number, OpCode, RegisterMachine, RuntimeError, Value,
+    VirtualMachine, VmBuilder, VmConfig,
+};
+
+// what a run left behind
+type Outcome<V> = (InterpretResult, Vec<V>, Option<RuntimeError<V>>, String);
+
+fn on_stack_machine<V: Number>(chunk: &Chunk<V>, config: VmConfig) -> Outcome<V> {
+    let output = Output::default();
+    let mut vm: VirtualMachine<V> = VmBuilder::default().config(config).output(Box::new(output.clone())).build();
+    let result = vm.interpret(chunk.clone());
+    (result, vm.stack().to_vec(), vm.runtime_error().cloned(), output.text())
+}
+
+fn on_register_machine<V: Number>(chunk: &Chunk<V>, config: VmConfig) -> Outcome<V> {
+    let output = Output::default();
+    let mut vm = RegisterMachine::<V>::new();
+    vm.set_config(config);
+    vm.set_output(Box::new(output.clone()));
+    let result = vm.interpret(chunk.clone());
+    (result, vm.stack().to_vec(), vm.runtime_error().cloned(), output.text())
+}
+
+// returns the shared result so callers can check the program did what they meant
+fn both_engines<V: Number>(chunk: &Chunk<V>, config: VmConfig) -> InterpretResult {
+    let stack = on_stack_machine(chunk, config);
+    assert_same(&on_register_machine(chunk, config), &stack, format_args!("{config:?}"));
+    stack.0
+}
+
+// every arithmetic mode, on the chunk as built and as optimized for that mode
+fn every_mode<V: Number>(chunk: &Chunk<V>) -> InterpretResult {
+    let result = both_engines(chunk, VmConfig::default());
+    for arithmetic in [Arithmetic::Wrapping, Arithmetic::Saturating, Arithmetic::Checked] {
+        let config = VmConfig { arithmetic, ..VmConfig::default() };
+        both_engines(chunk, config);
+        let mut optimized = chunk.clone();
+        optimized.optimize(arithmetic).unwrap();
+        both_engines(&optimized, config);
+    }
+    result
+}
+
+const SOURCES: &[&str] = &[
+    "1 + 2 * 3 - 4 / 5",
+    "(7 % 3) ** 2 - -4",
+    "print 1; print 2.5 * 2; 3",
+    "9223372036854775807 + 1",
+    "-(2 ** 100) >> 7 & ~3 | 5 ^ 6 << 2",
+    "1 / 0",
+    "print 1;\nprint 2;\n3 % 0",
+    "1 << -1",
+    "2.5 | 1",
+    "2.0 ** 1023 * 2 ** 0.5 * 2",
+];
+
+#[test]
+fn compiled_programs() {
+    for source in SOURCES {
+        for options in [CompileOptions::default(), CompileOptions::unoptimized()] {
+            every_mode(&compile_with(source, options).unwrap());
+        }
+    }
+}
+
+#[test]
+fn loops_and_shuffles() {
+    for n in [0, 1, 5, 40] {
+        assert_eq!(every_mode(&countdown(Value::Int(n), Value::Int(1))), InterpretResult::InterpretSuccess);
+    }
+    // counting down from 3 by 2 skips zero and overflows
+    let chunk = countdown(3u8, 2);
+    let config = |arithmetic| VmConfig { arithmetic, ..VmConfig::default() };
+    assert_eq!(both_engines(&chunk, config(Arithmetic::Checked)), InterpretResult::InterpretRuntimeError);
+    assert_eq!(both_engines(&countdown(3.5f64, 0.5), config(Arithmetic::Wrapping)), InterpretResult::InterpretSuccess);
+}
+
+#[test]
+fn limits() {
+    let chunk = compile_with("1 + (2 * (3 - (4 + 5)))", CompileOptions::unoptimized()).unwrap();
+    let shallow = VmConfig { max_stack: 3, ..VmConfig::default() };
+    assert_eq!(both_engines(&chunk, shallow), InterpretResult::InterpretRuntimeError);
+    assert_eq!(both_engines(&chunk, VmConfig { max_stack: 5, ..VmConfig::default() }), InterpretResult::InterpretSuccess);
+
+    // every heap limit up to what the big integers need, with a pop, a print
+    // and a branch releasing them along the way
+    let source = "print 2 ** 100; 2 ** 70 * 2 ** 70 - (2 ** 64 + 1) ** 2";
+    let chunk = compile_with(source, CompileOptions::unoptimized()).unwrap();
+    let mut b = ChunkBuilder::<Value>::default();
+    b.emit_constant(Value::Int(1).shl(&Value::Int(80), Arithmetic::Wrapping).unwrap()).emit_op(OpCode::OpDup).emit_op(OpCode::OpPop).emit_op(OpCode::OpDup);
+    let done = b.emit_jump_if_false();
+    b.emit_op(OpCode::OpNegate).emit_op(OpCode::OpDup).emit_op(OpCode::OpPrint);
+    b.patch(done);
+    b.emit_op(OpCode::OpReturn);
+    let shuffled = b.finish().unwrap();
+    for chunk in [chunk, shuffled] {
+        let results: Vec<InterpretResult> = (0..400)
+            .step_by(4)
+            .map(|max_heap_bytes| both_engines(&chunk, VmConfig { max_heap_bytes, ..VmConfig::default() }))
+            .collect();
+        assert!(results.contains(&InterpretResult::InterpretRuntimeError));
+        assert_eq!(results.last(), Some(&InterpretResult::InterpretSuccess));
+    }
+}